@@ -1,52 +1,80 @@
-use crate::bluetooth::gatt::{BleMessage, BluetoothGattDevice, BluetoothGattService};
-use crate::bluetooth::generic::BluetoothGenericService;
+use crate::bluetooth::diagnostics::{BleExplorer, GattInspection};
+use crate::bluetooth::gatt::{BleMessage, BluetoothGattService};
+use crate::bluetooth::lovense::{LovenseCommand, LovenseDeviceType};
+use crate::bluetooth::adv_protocol::{self, protocol};
+use crate::bluetooth::generic::{BluetoothGenericService, GenericToy, MAX_ADV_INTERVAL_MS, MIN_ADV_INTERVAL_MS};
+use crate::bluetooth::learn::AdvLearner;
+use crate::bluetooth::registry::DeviceRegistry;
+use crate::calibration::{CalibrationStage, CalibrationWizard};
+use crate::dither::{Dither, DEFAULT_DITHER_INTERVAL_MS};
+use crate::device::{intensity_to_level, OutputDevice, WriteTiming};
+use crate::device::virtual_device::{CommandLog, VirtualDevice, VirtualProtocol};
 use crate::osc_server::{OscFloatData, OscServer};
 use crate::remote::receiver::{RemoteControlServer, ServerMessage};
 use crate::remote::sender::RemoteControlSender;
-use crate::settings::{ControlMode, RemoteMode, Settings};
-use crate::speed_filter::SpeedFilter;
+use crate::routing::{InputValues, OscChannel};
+use crate::settings::{ControlMode, InputRoute, InputSource, RemoteMode, Settings};
 use base64::Engine;
 use eframe::Frame;
 use egui::{CentralPanel, Color32, SidePanel, TopBottomPanel};
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use url::Url;
 use wildmatch::WildMatch;
 
+const LOW_BATTERY_PERCENT: u8 = 20;
+const DEVICE_PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct AppContext {
     intensity: u8,
+    intensity_secondary: u8,
     last_intensity: u8,
-    last_max_intensity_perc: u8,
+    last_intensity_secondary: u8,
     settings: Settings,
     osc_server: OscServer,
     osc_value: OscFloatData,
+    osc_channels: HashMap<String, OscChannel>,
+    last_osc_update: Instant,
+    remote_speed: Option<f32>,
+    remote_speed_secondary: Option<f32>,
     remote_receiver: Option<RemoteControlServer>,
     remote_sender: RemoteControlSender,
     sender_url: Option<String>,
     sender_pairing_code: Option<String>,
     sender_state: RemoteSenderState,
     receiver_state: RemoteReceiverState,
-    selected_device: u16,
     gatt_service: BluetoothGattService,
     generic_service: BluetoothGenericService,
     adapter_initialized: bool,
     adapter_error: Option<String>,
-    adapter_status: Option<AdapterStatus>,
+    failed_adapters: Vec<(String, String)>, // adapter name, error
+    gatt_adapters: Vec<String>,
     found_devices: Vec<DeviceProfile>,
-    filter: SpeedFilter,
-    last_filter_update: Instant,
+    registry: DeviceRegistry,
+    explorer: BleExplorer,
+    explorer_status: Option<String>,
+    learner: AdvLearner,
+    learn_status: Option<String>,
+    learn_import_path: String,
+    calibration: Option<CalibrationWizard>,
     show_advanced_settings: bool,
+    show_routing: bool,
+    show_adapter_settings: bool,
+    show_keepalive_settings: bool,
+    show_generic_settings: bool,
+    show_explorer: bool,
+    show_learn: bool,
 }
 
 impl AppContext {
     pub fn new() -> Self {
         let settings = Settings::load_or_default().unwrap();
 
-        let mut osc_server = OscServer::new(settings.osc_port);
-        osc_server.set_pattern(WildMatch::new(&settings.osc_path));
+        let osc_server = OscServer::new(settings.osc_port);
 
         let (remote_server,receiver_state) = match &settings.ngrok_token {
             Some(ngrok_token) => {
-                let server = RemoteControlServer::new(&ngrok_token);
+                let server = RemoteControlServer::new(ngrok_token);
                 if let ControlMode::Remote(RemoteMode::Receiver) = &settings.mode {
                     server.start().unwrap();
                 }
@@ -55,76 +83,332 @@ impl AppContext {
             None => (None, RemoteReceiverState::NoToken)
         };
 
-        Self {
+        let mut generic_service = BluetoothGenericService::new(settings.adv_adapter.clone());
+        generic_service.set_idle_stop(settings.adv_idle_stop());
+        generic_service.set_options(settings.adv_options());
+
+        let mut context = Self {
             intensity: 0,
+            intensity_secondary: 0,
             last_intensity: 0,
-            last_max_intensity_perc: 0,
+            last_intensity_secondary: 0,
             settings,
             osc_server,
             osc_value: OscFloatData::default(),
+            osc_channels: HashMap::new(),
+            last_osc_update: Instant::now(),
+            remote_speed: None,
+            remote_speed_secondary: None,
             remote_receiver: remote_server,
             remote_sender: RemoteControlSender::new(),
             sender_url: None,
             sender_pairing_code: None,
             sender_state: RemoteSenderState::NotConnected,
             receiver_state,
-            selected_device: 0,
             gatt_service: BluetoothGattService::new(),
-            generic_service: BluetoothGenericService::new(),
+            generic_service,
             adapter_initialized: false,
             adapter_error: None,
-            adapter_status: None,
-            found_devices: vec![DeviceProfile::GenericDevice],
-            filter: SpeedFilter::new(0.05),
-            last_filter_update: Instant::now(),
+            failed_adapters: Vec::new(),
+            gatt_adapters: Vec::new(),
+            found_devices: Vec::new(),
+            registry: DeviceRegistry::default(),
+            explorer: BleExplorer::default(),
+            explorer_status: None,
+            learner: AdvLearner::default(),
+            learn_status: None,
+            learn_import_path: String::new(),
+            calibration: None,
             show_advanced_settings: false,
+            show_routing: false,
+            show_adapter_settings: false,
+            show_keepalive_settings: false,
+            show_generic_settings: false,
+            show_explorer: false,
+            show_learn: false,
+        };
+
+        context.reset_devices();
+        context.update_osc_channels();
+        context
+    }
+
+    fn default_source(&self) -> Option<InputSource> {
+        match &self.settings.mode {
+            ControlMode::Manual => Some(InputSource::Manual),
+            ControlMode::Osc => Some(InputSource::Osc(self.settings.osc_path.clone())),
+            ControlMode::Remote(RemoteMode::Sender) => self.settings.remote_sync_local.then_some(InputSource::Manual),
+            ControlMode::Remote(RemoteMode::Receiver) => Some(InputSource::Remote),
         }
     }
 
-    pub fn send_speed(&mut self, speed: f32) {
-        _ = match self.selected_device {
-            0 => self.generic_service.send_speed((speed * 7f32) as u8),
-            _ => self.gatt_service.send_speed((speed * 20f32) as u8),
+    /// Sends the routed input values to every enabled device
+    fn update_outputs(&mut self) {
+        let slider_steps = self.slider_steps().max(1) as f32;
+        let values = InputValues {
+            manual: Some(self.intensity as f32 / slider_steps),
+            manual_secondary: self.has_secondary_channel().then_some(self.intensity_secondary as f32 / slider_steps),
+            remote: self.remote_speed,
+            remote_secondary: self.remote_speed_secondary,
+            osc: self.osc_channels
+                .iter()
+                .map(|(pattern, channel)| (pattern.clone(), channel.speed()))
+                .collect(),
         };
+
+        let default_source = self.default_source();
+        let secondary_source = default_source.as_ref().map(|source| source.secondary());
+        let speed_scale = self.settings.max_intensity_percent as f32 / 100.0;
+        let now = Instant::now();
+
+        for profile in &mut self.found_devices {
+            let identifier = profile.device.identifier();
+            let device_settings = self.settings.device_settings(&identifier);
+            // The calibration wizard drives the device directly
+            if !device_settings.enabled || self.calibration.as_ref().is_some_and(|wizard| wizard.identifier() == identifier) {
+                continue;
+            }
+
+            profile.device.set_write_timing(WriteTiming {
+                min_interval: Duration::from_millis(device_settings.min_write_interval_ms as u64),
+                keepalive: profile.device.protocol().and_then(|protocol| self.settings.keepalive_for(&protocol)),
+            });
+
+            let scale = speed_scale * device_settings.max_intensity_percent as f32 / 100.0;
+            let steps = profile.device.steps().max(1);
+            let motors = profile.motors();
+
+            // Dithering never switches levels faster than the device can take them
+            let dither_interval = device_settings.dither_interval_ms.map(|interval_ms| {
+                Duration::from_millis(interval_ms.max(device_settings.min_write_interval_ms) as u64).max(profile.device.min_level_duration())
+            });
+            let mut output = |motor: u8, value: f32| {
+                let value = device_settings.calibration.map_or(value * scale, |calibration| calibration.apply(value * scale, steps));
+                match dither_interval {
+                    Some(interval) => profile.dither.entry(motor).or_default().level(value, steps, interval, now) as f32 / steps as f32,
+                    None => value,
+                }
+            };
+            let primary = values.resolve(&self.settings.routes, &identifier, 1, default_source.as_ref());
+
+            if motors <= 1 {
+                if let Some(value) = primary {
+                    _ = profile.device.set_intensity(output(1, value));
+                }
+                continue;
+            }
+
+            // Without explicit routes, motors without their own input mirror the first one
+            let has_routes = self.settings.routes.iter().any(|route| route.device == identifier);
+            for motor in 1..=motors {
+                let value = match motor {
+                    1 => primary,
+                    _ => values
+                        .resolve(&self.settings.routes, &identifier, motor, secondary_source.as_ref())
+                        .or(if has_routes { None } else { primary }),
+                };
+
+                if let Some(value) = value {
+                    _ = profile.device.set_motor_intensity(motor, output(motor, value));
+                }
+            }
+        }
+    }
+
+    /// Whether a second manual/remote input channel is needed for dual-motor devices
+    fn has_secondary_channel(&self) -> bool {
+        let has_dual_motor = self.found_devices
+            .iter()
+            .any(|profile| profile.motors() > 1 && self.settings.device_settings(&profile.device.identifier()).enabled);
+        let has_secondary_route = self.settings.routes
+            .iter()
+            .any(|route| matches!(route.source, InputSource::ManualSecondary | InputSource::RemoteSecondary));
+
+        has_dual_motor || has_secondary_route
+    }
+
+    fn update_osc_channels(&mut self) {
+        let mut patterns = vec![self.settings.osc_path.clone()];
+        for route in &self.settings.routes {
+            if let InputSource::Osc(pattern) = &route.source
+                && !patterns.contains(pattern) {
+                patterns.push(pattern.clone());
+            }
+        }
+
+        self.osc_channels.retain(|pattern, _| patterns.contains(pattern));
+        for pattern in &patterns {
+            self.osc_channels
+                .entry(pattern.clone())
+                .or_insert_with(|| OscChannel::new(pattern));
+        }
+
+        // The pattern selector is read directly, without a channel
+        let mut server_patterns = patterns;
+        if !self.settings.osc_pattern_path.is_empty() && !server_patterns.contains(&self.settings.osc_pattern_path) {
+            server_patterns.push(self.settings.osc_pattern_path.clone());
+        }
+        self.osc_server.set_patterns(server_patterns.iter().map(|pattern| WildMatch::new(pattern)).collect());
+    }
+
+    fn slider_steps(&self) -> u8 {
+        if let ControlMode::Remote(RemoteMode::Sender) = &self.settings.mode {
+            return 20;
+        }
+
+        self.found_devices
+            .iter()
+            .filter(|profile| self.settings.device_settings(&profile.device.identifier()).enabled)
+            .map(|profile| profile.device.steps())
+            .max()
+            .unwrap_or(20)
+    }
+
+    fn set_device_enabled(&mut self, index: usize, enabled: bool) {
+        let profile = self.found_devices.get_mut(index).unwrap();
+        let identifier = profile.device.identifier();
+
+        let adapter = self.settings.gatt_adapter_for(&identifier);
+        self.settings.device_settings.entry(identifier).or_default().enabled = enabled;
+        if enabled {
+            Self::connect_device(profile, adapter);
+        } else {
+            _ = profile.device.set_intensity(0.0);
+            _ = profile.device.disconnect();
+            profile.status = DeviceStatus::NotConnected;
+            profile.error.take();
+        }
+
+        self.settings.save().unwrap();
+    }
+
+    fn connect_device(profile: &mut DeviceProfile, adapter: Option<String>) {
+        profile.device.set_adapter(adapter);
+        _ = profile.device.connect();
+        _ = profile.device.set_intensity(0.0);
+
+        // Devices without a BLE connection are ready immediately
+        if profile.device.ble_address().is_none() {
+            profile.status = DeviceStatus::Connected;
+        }
+    }
+
+    fn reset_devices(&mut self) {
+        self.found_devices.clear();
+        self.registry.clear();
+        for toy in &self.settings.generic_toys {
+            if let Ok(device) = self.generic_service.create_device(toy.clone()) {
+                self.found_devices.push(DeviceProfile::new(device));
+            }
+        }
+        self.found_devices.push(DeviceProfile::new_virtual(VirtualProtocol::Lovense));
+        self.found_devices.push(DeviceProfile::new_virtual(VirtualProtocol::Generic));
+
+        for profile in &mut self.found_devices {
+            let identifier = profile.device.identifier();
+            if self.settings.device_settings(&identifier).enabled {
+                Self::connect_device(profile, self.settings.gatt_adapter_for(&identifier));
+            }
+        }
     }
 
-    fn connect_to_selected(&mut self) {
-    self.gatt_service.disconnect().unwrap();
+    /// Applies edited generic toys, toys that kept their identifier keep advertising without interruption
+    fn reload_generic_devices(&mut self, previous: &[GenericToy]) {
+        for toy in previous.iter().filter(|toy| !self.settings.generic_toys.iter().any(|current| current.identifier == toy.identifier)) {
+            if let Some(index) = self.found_devices.iter().position(|profile| profile.device.identifier() == toy.identifier) {
+                _ = self.found_devices.remove(index).device.disconnect();
+            }
+        }
 
-        let index = self.selected_device as usize;
-        let device = self.found_devices.get(index).unwrap();
+        for toy in self.settings.generic_toys.clone() {
+            let Ok(device) = self.generic_service.create_device(toy.clone()) else {
+                continue;
+            };
+
+            match self.find_device_mut(&toy.identifier) {
+                Some(profile) => {
+                    profile.device = Box::new(device);
+                    // Programs of another protocol do not carry over
+                    if profile.pattern.is_some() && profile.device.set_pattern(profile.pattern).is_err() {
+                        profile.pattern.take();
+                    }
+                }
+                None => {
+                    // New toys are listed after the existing generic ones
+                    let index = self.found_devices
+                        .iter()
+                        .rposition(|profile| previous.iter().any(|toy| toy.identifier == profile.device.identifier()))
+                        .map_or(0, |index| index + 1);
+                    let mut profile = DeviceProfile::new(device);
+                    if self.settings.device_settings(&toy.identifier).enabled {
+                        Self::connect_device(&mut profile, None);
+                    }
+                    self.found_devices.insert(index, profile);
+                }
+            }
+        }
+    }
 
-        match device {
-            DeviceProfile::GenericDevice => {
-                self.gatt_service.disconnect().unwrap();
-                self.settings.last_ble_mac.take();
-                self.settings.save().unwrap();
-                self.adapter_status.take();
+    /// Reconnects enabled GATT devices through their currently selected adapter
+    fn reconnect_gatt_devices(&mut self, identifier: Option<&str>) {
+        for profile in &mut self.found_devices {
+            let device_identifier = profile.device.identifier();
+            if profile.device.ble_address().is_none() || identifier.is_some_and(|identifier| identifier != device_identifier) {
+                continue;
             }
-            DeviceProfile::GattDevice(device) => {
-                self.gatt_service.connect(device).unwrap();
-                self.settings.last_ble_mac.replace(device.device_address.clone());
-                self.settings.save().unwrap();
-                self.send_speed(0.0f32);
+            if !self.settings.device_settings(&device_identifier).enabled {
+                continue;
             }
+
+            _ = profile.device.disconnect();
+            Self::connect_device(profile, self.settings.gatt_adapter_for(&device_identifier));
         }
     }
 
+    fn find_device_mut(&mut self, identifier: &str) -> Option<&mut DeviceProfile> {
+        self.found_devices
+            .iter_mut()
+            .find(|profile| profile.device.identifier() == identifier)
+    }
+
     fn handle_osc(&mut self) {
-        if let Some(val) = self.osc_server.try_read_value() {
-            self.osc_value = val;
+        while let Some(val) = self.osc_server.try_read_value() {
+            for channel in self.osc_channels.values_mut() {
+                if channel.matches(&val.address) {
+                    channel.push_value(val.value);
+                }
+            }
+
+            if !self.settings.osc_pattern_path.is_empty() && WildMatch::new(&self.settings.osc_pattern_path).matches(&val.address) {
+                self.select_osc_pattern(val.value);
+            }
+
+            if self.osc_channels.get(&self.settings.osc_path).is_some_and(|channel| channel.matches(&val.address)) {
+                self.osc_value = val;
+            }
         }
 
-        if self.settings.mode == ControlMode::Osc {
-            let delta_time = self.last_filter_update.elapsed().as_secs_f32();
-            self.last_filter_update = Instant::now();
+        let delta_time = self.last_osc_update.elapsed().as_secs_f32();
+        self.last_osc_update = Instant::now();
 
-            let scaled_value = ((self.osc_value.value - self.settings.osc_range_start) / (self.settings.osc_range_end - self.settings.osc_range_start)).clamp(0.0, 1.0);
-            let speed_value = self.filter.update(scaled_value, delta_time).clamp(0.0, 5.0);
+        for channel in self.osc_channels.values_mut() {
+            channel.update(delta_time, self.settings.osc_range_start, self.settings.osc_range_end);
+        }
+    }
 
-            let speed_scale = self.settings.max_intensity_percent as f32 / 100.0;
+    /// Selects pattern `value` (1-based) on every device that has it, 0 or unknown patterns play plain levels
+    fn select_osc_pattern(&mut self, value: f32) {
+        let index = value.round() as i64;
+        for profile in &mut self.found_devices {
+            let patterns = profile.device.patterns().len() as i64;
+            if patterns == 0 {
+                continue;
+            }
 
-            self.send_speed(speed_value / 5.0 * speed_scale);
+            let pattern = (1..=patterns).contains(&index).then(|| (index - 1) as usize);
+            if profile.pattern != pattern && profile.device.set_pattern(pattern).is_ok() {
+                profile.pattern = pattern;
+            }
         }
     }
 
@@ -136,68 +420,360 @@ impl AppContext {
                     self.adapter_initialized = false;
                     self.adapter_error.replace(error);
                 }
+                BleMessage::AdapterFailed(adapter, error) => self.failed_adapters.push((adapter, error)),
+                BleMessage::AdaptersFound(adapters) => self.gatt_adapters = adapters,
+                BleMessage::Advertisement(adapter, advertisement) => self.explorer.advertisement(&adapter, advertisement),
+                BleMessage::GattServices(address, services) => self.explorer.set_gatt(&address, GattInspection::from_services(services)),
                 BleMessage::DeviceDiscovered(device) => {
+                    // Devices in range of several adapters are reported by each of them, and again on every update
                     let address = device.device_address.clone();
-                    self.found_devices.push(DeviceProfile::GattDevice(device));
+                    self.registry.seen(&address, device.rssi, Instant::now());
+                    if self.find_device_mut(&address).is_some() {
+                        continue;
+                    }
+                    match self.gatt_service.create_device(device) {
+                        Ok(device) => self.found_devices.push(DeviceProfile::new(device)),
+                        Err(_) => continue,
+                    }
 
-                    if let Some(last_device_mac) = &self.settings.last_ble_mac {
-                        if last_device_mac == &address && self.selected_device == 0 {
-                            let index = self.found_devices.len() - 1;
-                            self.selected_device = index as u16;
-                            self.connect_to_selected();
-                        }
+                    let device_settings = self.settings.device_settings(&address);
+                    let index = self.found_devices.len() - 1;
+                    self.found_devices[index].alias = device_settings.alias;
+                    if device_settings.enabled {
+                        self.set_device_enabled(index, true);
+                    }
+                }
+                BleMessage::DeviceConnecting(address) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.status = DeviceStatus::Connecting;
                     }
                 }
-                BleMessage::DeviceConnecting(device) => {
-                    self.adapter_status.replace(AdapterStatus::Connecting(device));
+                BleMessage::DeviceConnected(address) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        // Re-applies the current intensity after a reconnect
+                        profile.device.reset_levels();
+                        profile.status = DeviceStatus::Connected;
+                        profile.error.take();
+                    }
+                }
+                BleMessage::ConnectFailed(address, error) | BleMessage::CommandStatus(address, Some(error)) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.error.replace(error);
+                    }
+                }
+                BleMessage::CommandStatus(address, None) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.error.take();
+                    }
+                }
+                BleMessage::Reconnecting(address, attempt) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.status = DeviceStatus::Reconnecting(attempt);
+                    }
+                }
+                BleMessage::DeviceDisconnected(address) => {
+                    self.registry.touch(&address, Instant::now());
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.status = DeviceStatus::NotConnected;
+                        profile.battery.take();
+                    }
                 }
-                BleMessage::DeviceConnected(device) => {
-                    self.adapter_status.replace(AdapterStatus::Connected(device));
+                BleMessage::BatteryLevel(address, level) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.battery.replace(level);
+                    }
                 }
-                BleMessage::DeviceDisconnected(_) => {
-                    self.adapter_status.replace(AdapterStatus::NotConnected);
+                BleMessage::DeviceType(address, device_type) => {
+                    if let Some(profile) = self.find_device_mut(&address) {
+                        profile.device_type.replace(device_type);
+                    }
                 }
             }
         }
+
+        // Toys stop advertising while connected, only idle ones are removed once they are out of range
+        let now = Instant::now();
+        for profile in &self.found_devices {
+            if !matches!(profile.status, DeviceStatus::NotConnected) && let Some(address) = profile.device.ble_address() {
+                self.registry.touch(&address, now);
+            }
+        }
+        let expired = self.registry.expire(now, DEVICE_PRESENCE_TIMEOUT);
+        if !expired.is_empty() {
+            self.found_devices.retain(|profile| profile.device.ble_address().is_none_or(|address| !expired.contains(&address)));
+        }
     }
 
-    fn handle_remote_receiver(&mut self) {
-        if let Some(remote_receiver) = &mut self.remote_receiver {
-            while let Some(message) = remote_receiver.recv_message() {
-                match message {
-                    ServerMessage::Started { url, token } => {
-                        self.sender_url.replace(url);
-                        self.sender_pairing_code.replace(token);
-                        self.receiver_state = RemoteReceiverState::Connected;
-                    }
-                    ServerMessage::Stopped => {
-                        _ = self.sender_url.take();
-                        _ = self.sender_pairing_code.take();
-                        self.receiver_state = RemoteReceiverState::NotConnected;
-                    }
-                    ServerMessage::NewConnection => {
-                        self.receiver_state = RemoteReceiverState::Active;
-                    }
-                    ServerMessage::SpeedReceived { speed } => {
-                        let intensity = match self.selected_device {
-                            0 => (speed * 7.0) as u8,
-                            _ => (speed * 20.0) as u8,
-                        };
+    fn draw_explorer(&mut self, ctx: &egui::Context) {
+        let mut open = true;
+        let mut inspect = None;
+        egui::Window::new("BLE Explorer")
+            .open(&mut open)
+            .default_size([420.0, 360.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} devices", self.explorer.devices().len()));
+                    if ui.button("Clear").clicked() {
+                        self.explorer.clear();
+                    }
+                    if ui.button("Copy JSON").clicked() {
+                        let result = self.explorer.to_json().and_then(|json| Ok(arboard::Clipboard::new()?.set_text(json)?));
+                        self.explorer_status.replace(match result {
+                            Ok(()) => "Copied to clipboard".into(),
+                            Err(error) => format!("Copy failed: {}", error),
+                        });
+                    }
+                    if ui.button("Export JSON").clicked() {
+                        self.explorer_status.replace(match self.explorer.export() {
+                            Ok(path) => format!("Saved to {}", path.display()),
+                            Err(error) => format!("Export failed: {}", error),
+                        });
+                    }
+                });
+                if let Some(status) = &self.explorer_status {
+                    ui.colored_label(Color32::GRAY, status);
+                }
+                ui.separator();
+
+                egui::ScrollArea::vertical().id_salt("explorer").show(ui, |ui| {
+                    for device in self.explorer.devices() {
+                        let rssi = device.rssi.map_or("?".into(), |rssi| rssi.to_string());
+                        let header = format!("{} ({}, {} dBm)", device.name.as_deref().unwrap_or("Unnamed"), device.address, rssi);
+                        egui::CollapsingHeader::new(header).id_salt(&device.address).show(ui, |ui| {
+                            ui.label(format!("Adapter: {}", device.adapter));
+                            for service in &device.services {
+                                ui.label(format!("Service: {}", service));
+                            }
+                            for (company_id, data) in &device.manufacturer_data {
+                                ui.label(format!("Manufacturer data {}: {}", company_id, data));
+                            }
+                            for (service, data) in &device.service_data {
+                                ui.label(format!("Service data {}: {}", service, data));
+                            }
+
+                            match &device.gatt {
+                                None => {
+                                    if ui.button("Inspect GATT").clicked() {
+                                        inspect = Some((device.address.clone(), device.adapter.clone()));
+                                    }
+                                }
+                                Some(GattInspection::Pending) => {
+                                    ui.colored_label(Color32::ORANGE, "Connecting...");
+                                }
+                                Some(GattInspection::Failed(error)) => {
+                                    ui.colored_label(Color32::RED, error);
+                                    if ui.button("Retry").clicked() {
+                                        inspect = Some((device.address.clone(), device.adapter.clone()));
+                                    }
+                                }
+                                Some(GattInspection::Services(services)) => {
+                                    for service in services {
+                                        ui.label(format!("GATT service {}", service.uuid));
+                                        for characteristic in &service.characteristics {
+                                            ui.horizontal(|ui| {
+                                                ui.add_space(16.0);
+                                                ui.label(&characteristic.uuid);
+                                                ui.colored_label(Color32::GRAY, characteristic.properties.join(", "));
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                        });
+                    }
+                });
+            });
+
+        if let Some((address, adapter)) = inspect {
+            self.explorer.set_gatt(&address, GattInspection::Pending);
+            self.gatt_service.inspect(&address, Some(adapter));
+        }
+
+        if !open {
+            self.show_explorer = false;
+            self.update_diagnostics();
+        }
+    }
+
+    /// Advertisements are only forwarded while the explorer needs them
+    fn update_diagnostics(&self) {
+        self.gatt_service.set_diagnostics(self.show_explorer);
+    }
+
+    fn handle_learned(&mut self) {
+        while let Some((company_id, data)) = self.generic_service.fetch_learned() {
+            self.learner.manufacturer_data(company_id, &data);
+        }
+    }
+
+    fn draw_learn(&mut self, ctx: &egui::Context) {
+        let mut open = true;
+        let mut learned_toy = None;
+        egui::Window::new("Learn ADV remote")
+            .open(&mut open)
+            .default_size([360.0, 300.0])
+            .show(ctx, |ui| {
+                ui.label("Press buttons on the stock remote near this computer, or import a btsnoop HCI log captured while using the remote app.");
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut self.learn_import_path).hint_text("btsnoop_hci.log").desired_width(200.0));
+                    if ui.button("Import").clicked() {
+                        self.learn_status.replace(match self.learner.import_btsnoop(self.learn_import_path.trim().as_ref()) {
+                            Ok(imported) => format!("Imported {} advertisements", imported),
+                            Err(error) => format!("Import failed: {}", error),
+                        });
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.learner.clear();
+                        self.learn_status.take();
+                    }
+                });
+                if let Some(status) = &self.learn_status {
+                    ui.colored_label(Color32::GRAY, status);
+                }
+                ui.separator();
+
+                egui::ScrollArea::vertical().id_salt("learn").show(ui, |ui| {
+                    let remotes = self.learner.remotes();
+                    if remotes.is_empty() {
+                        ui.colored_label(Color32::GRAY, "Waiting for remote advertisements...");
+                    }
 
-                        _ = match self.selected_device {
-                            0 => self.generic_service.send_speed(intensity),
-                            _ => self.gatt_service.send_speed(intensity),
+                    for remote in remotes {
+                        let protocol = protocol(&remote.protocol);
+                        let command = match remote.output {
+                            Some(output) => protocol.output_name(output),
+                            None => hex::encode(remote.raw_command),
                         };
+                        ui.label(format!("Address {}, company ID 0x{:04X}, {}", hex::encode(remote.address), remote.company_id, protocol.name));
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            ui.colored_label(Color32::GRAY, format!("Last command: {} ({} seen)", command, remote.count));
+                            if self.settings.generic_toys.iter().any(|toy| toy.address == remote.address && toy.company_id == remote.company_id) {
+                                ui.colored_label(Color32::GREEN, "Saved");
+                            } else if ui.small_button("Save as generic toy").clicked() {
+                                learned_toy = Some((remote.address, remote.company_id, remote.protocol.clone()));
+                            }
+                        });
+                    }
+                });
+            });
+
+        if let Some((address, company_id, protocol)) = learned_toy {
+            let previous = self.settings.generic_toys.clone();
+            let identifier = GenericToy::next_identifier(&previous);
+            self.settings.generic_toys.push(GenericToy {
+                name: format!("Generic Device {}", hex::encode(address)),
+                identifier,
+                address,
+                company_id,
+                protocol,
+            });
+            self.settings.save().unwrap();
+            self.reload_generic_devices(&previous);
+        }
+
+        if !open {
+            self.show_learn = false;
+            self.generic_service.set_learning(false);
+        }
+    }
+
+    fn draw_calibration(&mut self, ctx: &egui::Context) {
+        let Some(wizard) = &mut self.calibration else {
+            return;
+        };
+        let Some(profile) = self.found_devices.iter_mut().find(|profile| profile.device.identifier() == wizard.identifier()) else {
+            self.calibration = None;
+            return;
+        };
+
+        let mut open = true;
+        let mut cancelled = false;
+        egui::Window::new(format!("Calibrate {}", profile.name()))
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                match wizard.stage() {
+                    CalibrationStage::Minimum => ui.label("Raise the level until you can just feel the device."),
+                    CalibrationStage::Maximum { .. } => ui.label("Raise the level until it is as strong as is still comfortable."),
+                    CalibrationStage::Done(_) => return,
+                };
 
-                        self.intensity = intensity;
-                        self.receiver_state = RemoteReceiverState::Active;
+                ui.horizontal(|ui| {
+                    if ui.button("-").clicked() {
+                        wizard.step_down();
                     }
-                    ServerMessage::Error { message } => {
-                        self.receiver_state = RemoteReceiverState::Error(message);
+                    ui.label(format!("Level {} / {}", wizard.level(), wizard.steps()));
+                    if ui.button("+").clicked() {
+                        wizard.step_up();
                     }
-                    ServerMessage::Initializing => {
-                        self.receiver_state = RemoteReceiverState::Connecting;
+                });
+
+                let confirm_label = match wizard.stage() {
+                    CalibrationStage::Minimum => "Set minimum",
+                    _ => "Set maximum",
+                };
+                ui.horizontal(|ui| {
+                    if ui.button(confirm_label).clicked() {
+                        wizard.confirm();
+                    }
+                    if ui.button("Cancel").clicked() {
+                        cancelled = true;
                     }
+                });
+            });
+
+        _ = profile.device.set_intensity(wizard.intensity());
+
+        if let CalibrationStage::Done(calibration) = wizard.stage() {
+            self.settings.device_settings.entry(wizard.identifier().into()).or_default().calibration = Some(calibration);
+            self.settings.save().unwrap();
+            self.calibration = None;
+        } else if !open || cancelled {
+            _ = profile.device.set_intensity(0.0);
+            self.calibration = None;
+        }
+    }
+
+    /// Finishes the version handshake with the receiver, connecting does not wait for it
+    fn handle_remote_sender(&mut self) {
+        if let ControlMode::Remote(RemoteMode::Sender) = self.settings.mode && let Err(error) = self.remote_sender.poll() {
+            self.sender_state = RemoteSenderState::Error(format!("{}", error));
+        }
+    }
+
+    fn handle_remote_receiver(&mut self) {
+        while let Some(message) = self.remote_receiver.as_mut().and_then(|receiver| receiver.recv_message()) {
+            match message {
+                ServerMessage::Started { url, token } => {
+                    self.sender_url.replace(url);
+                    self.sender_pairing_code.replace(token);
+                    self.receiver_state = RemoteReceiverState::Connected;
+                }
+                ServerMessage::Stopped => {
+                    _ = self.sender_url.take();
+                    _ = self.sender_pairing_code.take();
+                    self.receiver_state = RemoteReceiverState::NotConnected;
+                }
+                ServerMessage::NewConnection => {
+                    self.receiver_state = RemoteReceiverState::Active;
+                }
+                ServerMessage::SpeedReceived { channel: 0, speed } => {
+                    self.remote_speed.replace(speed);
+                    self.intensity = intensity_to_level(speed, self.slider_steps());
+                    self.receiver_state = RemoteReceiverState::Active;
+                }
+                ServerMessage::SpeedReceived { speed, .. } => {
+                    self.remote_speed_secondary.replace(speed);
+                    self.intensity_secondary = intensity_to_level(speed, self.slider_steps());
+                    self.receiver_state = RemoteReceiverState::Active;
+                }
+                ServerMessage::Error { message } => {
+                    self.receiver_state = RemoteReceiverState::Error(message);
+                }
+                ServerMessage::Initializing => {
+                    self.receiver_state = RemoteReceiverState::Connecting;
                 }
             }
         }
@@ -209,6 +785,8 @@ impl eframe::App for AppContext {
         // Logic
         self.handle_osc();
         self.handle_ble();
+        self.handle_learned();
+        self.handle_remote_sender();
         self.handle_remote_receiver();
 
         // Draw top bar
@@ -231,17 +809,26 @@ impl eframe::App for AppContext {
                             ui.colored_label(Color32::RED, "Adapter error");
                         }
                         if ui.button("Try again").clicked() {
+                            self.failed_adapters.clear();
                             self.gatt_service.start_ble();
                             self.generic_service.start_ble();
+                            self.reset_devices();
                         }
                     });
                     ui.add_space(2.0);
                 }
+                for (adapter, error) in &self.failed_adapters {
+                    ui.colored_label(Color32::YELLOW, format!("{} unavailable: {}", adapter, error));
+                }
             });
         });
 
         // Draw intensity slider
-        if self.settings.mode != ControlMode::Osc {
+        let has_manual_route = self.settings.routes
+            .iter()
+            .any(|route| matches!(route.source, InputSource::Manual | InputSource::ManualSecondary));
+        let has_secondary_channel = self.has_secondary_channel();
+        if self.settings.mode != ControlMode::Osc || has_manual_route {
             SidePanel::right("side_panel")
                 .resizable(false)
                 .default_width(0.0)
@@ -254,12 +841,8 @@ impl eframe::App for AppContext {
                         ui.vertical(|ui| {
                             ui.add_space(20.0);
                             ui.spacing_mut().slider_width = available_height - 40.0;
-                            let slider_max = match (&self.settings.mode, self.selected_device) {
-                                (&ControlMode::Remote(RemoteMode::Sender), _) => 20,
-                                (_, 0) => 7,
-                                (_, _) => 20,
-                            };
-                            ui.add_enabled(if let ControlMode::Remote(RemoteMode::Receiver) = self.settings.mode { false } else { true },
+                            let slider_max = self.slider_steps();
+                            ui.add_enabled(!matches!(self.settings.mode, ControlMode::Remote(RemoteMode::Receiver)),
                                            egui::Slider::new(&mut self.intensity, 0..=slider_max)
                                                .vertical()
                                                .show_value(false)
@@ -267,6 +850,22 @@ impl eframe::App for AppContext {
                             );
                             ui.add_space(20.0);
                         });
+
+                        // Second motor of dual-motor devices
+                        if has_secondary_channel {
+                            ui.vertical(|ui| {
+                                ui.add_space(20.0);
+                                ui.spacing_mut().slider_width = available_height - 40.0;
+                                let slider_max = self.slider_steps();
+                                ui.add_enabled(!matches!(self.settings.mode, ControlMode::Remote(RemoteMode::Receiver)),
+                                               egui::Slider::new(&mut self.intensity_secondary, 0..=slider_max)
+                                                   .vertical()
+                                                   .show_value(false)
+                                                   .trailing_fill(true),
+                                );
+                                ui.add_space(20.0);
+                            });
+                        }
                         ui.add_space(4.0);
                     });
                 });
@@ -283,52 +882,312 @@ impl eframe::App for AppContext {
                         if ui.button("Manual").clicked() {
                             self.settings.mode = ControlMode::Manual;
                             self.settings.save().unwrap();
-                            self.remote_receiver.as_mut().and_then(|receiver| Some(receiver.stop()));
+                            self.remote_receiver.as_mut().map(|receiver| receiver.stop());
                         }
                     });
                     ui.add_enabled_ui(self.settings.mode != ControlMode::Osc, |ui| {
                         if ui.button("Osc").clicked() {
                             self.settings.mode = ControlMode::Osc;
                             self.settings.save().unwrap();
-                            self.remote_receiver.as_mut().and_then(|receiver| Some(receiver.stop()));
+                            self.remote_receiver.as_mut().map(|receiver| receiver.stop());
                         }
                     });
                     ui.add_enabled_ui(self.settings.mode == ControlMode::Manual || self.settings.mode == ControlMode::Osc, |ui| {
                         if ui.button("Remote").clicked() {
                             self.settings.mode = ControlMode::Remote(RemoteMode::Sender);
                             self.settings.save().unwrap();
-                            self.remote_receiver.as_mut().and_then(|receiver| Some(receiver.stop()));
+                            self.remote_receiver.as_mut().map(|receiver| receiver.stop());
                         }
                     });
                 });
 
                 ui.add_space(10.0);
 
-                // BLE Device selector
-                ui.horizontal(|ui| {
-                    let selected_device = self.found_devices
-                        .iter()
-                        .nth(self.selected_device as usize);
-
-                    ui.label("Device:");
-                    egui::ComboBox::from_id_salt("device_selector")
-                        .selected_text(selected_device.and_then(|d| Some(d.get_name())).unwrap())
-                        .show_ui(ui, |ui| {
-                            for i in 0..self.found_devices.len() {
-                                let device = self.found_devices.get(i).unwrap();
-                                if ui.selectable_value(&mut self.selected_device, i as u16, device.get_name()).clicked() {
-                                    self.connect_to_selected();
+                // BLE Device list
+                ui.label("Devices:");
+                let mut toggled_device = None;
+                let mut save_device_settings = false;
+                let mut changed_adapter_device = None;
+                let mut start_calibration = None;
+                egui::ScrollArea::vertical().id_salt("device_list").max_height(140.0).show(ui, |ui| {
+                    for (i, profile) in self.found_devices.iter_mut().enumerate() {
+                        let identifier = profile.device.identifier();
+                        let device_settings = self.settings.device_settings(&identifier);
+
+                        ui.horizontal(|ui| {
+                            let mut enabled = device_settings.enabled;
+                            if ui.checkbox(&mut enabled, profile.name()).changed() {
+                                toggled_device = Some((i, enabled));
+                            }
+
+                            if device_settings.enabled {
+                                match profile.status {
+                                    DeviceStatus::NotConnected => ui.colored_label(Color32::RED, "Not connected"),
+                                    DeviceStatus::Connecting => ui.colored_label(Color32::ORANGE, "Connecting..."),
+                                    DeviceStatus::Reconnecting(attempt) => ui.colored_label(Color32::ORANGE, format!("Reconnecting (attempt {})", attempt)),
+                                    DeviceStatus::Connected if profile.error.is_some() => ui.colored_label(Color32::ORANGE, "Commands failing"),
+                                    DeviceStatus::Connected => ui.colored_label(Color32::GREEN, "Connected!"),
+                                };
+                            }
+
+                            if let Some(rssi) = profile.device.ble_address().and_then(|address| self.registry.presence(&address)?.rssi) {
+                                ui.colored_label(Color32::GRAY, format!("{} dBm", rssi));
+                            }
+                        });
+
+                        if device_settings.enabled && let Some(error) = &profile.error {
+                            ui.horizontal(|ui| {
+                                ui.add_space(24.0);
+                                ui.colored_label(Color32::GRAY, error);
+                            });
+                        }
+
+                        // Battery level and firmware reported by the toy
+                        if device_settings.enabled && (profile.battery.is_some() || profile.device_type.is_some()) {
+                            ui.horizontal(|ui| {
+                                ui.add_space(24.0);
+                                match profile.battery {
+                                    Some(battery) if battery <= LOW_BATTERY_PERCENT => {
+                                        ui.colored_label(Color32::RED, format!("Battery low: {}%", battery));
+                                    }
+                                    Some(battery) => {
+                                        ui.label(format!("Battery: {}%", battery));
+                                    }
+                                    None => {}
+                                }
+                                if let Some(device_type) = &profile.device_type {
+                                    ui.colored_label(Color32::GRAY, format!("Firmware {}", device_type.firmware));
                                 }
+                            });
+                        }
+
+                        if !device_settings.enabled {
+                            continue;
+                        }
+
+                        // Persistent name of GATT devices
+                        if profile.device.ble_address().is_some() {
+                            ui.horizontal(|ui| {
+                                ui.add_space(24.0);
+                                ui.label("Alias:");
+                                let mut alias = device_settings.alias.clone().unwrap_or_default();
+                                let response = ui.add(
+                                    egui::TextEdit::singleline(&mut alias)
+                                        .hint_text(profile.default_name())
+                                        .desired_width(120.0),
+                                );
+                                if response.changed() {
+                                    profile.alias = (!alias.trim().is_empty()).then_some(alias);
+                                    self.settings.device_settings.entry(identifier.clone()).or_default().alias = profile.alias.clone();
+                                    save_device_settings = true;
+                                }
+                            });
+                        }
+
+                        // Per-device intensity scaling
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            ui.label("Max:");
+                            let mut max_intensity_percent = device_settings.max_intensity_percent;
+                            let response = ui.add(
+                                egui::DragValue::new(&mut max_intensity_percent)
+                                    .speed(0.1)
+                                    .range(0..=100),
+                            );
+                            if response.changed() {
+                                self.settings.device_settings.entry(identifier.clone()).or_default().max_intensity_percent = max_intensity_percent;
+                                save_device_settings = true;
+                            }
+                            ui.label("%");
+                        });
+
+                        // Perceptible level range found by the calibration wizard
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            match device_settings.calibration {
+                                Some(calibration) => {
+                                    ui.label(format!("Calibrated: {}-{}", calibration.min_level, calibration.max_level));
+                                    if ui.link("Reset").clicked() {
+                                        self.settings.device_settings.entry(identifier.clone()).or_default().calibration = None;
+                                        save_device_settings = true;
+                                    }
+                                }
+                                None => {
+                                    ui.colored_label(Color32::GRAY, "Not calibrated");
+                                }
+                            }
+                            let connected = matches!(profile.status, DeviceStatus::Connected);
+                            if ui.add_enabled(connected && self.calibration.is_none(), egui::Button::new("Calibrate")).clicked() {
+                                start_calibration = Some(CalibrationWizard::new(identifier.clone(), profile.device.steps()));
+                            }
+                        });
+
+                        // Built-in programs played instead of plain levels
+                        let patterns = profile.device.patterns();
+                        if !patterns.is_empty() {
+                            ui.horizontal(|ui| {
+                                ui.add_space(24.0);
+                                ui.label("Pattern:");
+                                let mut pattern = profile.pattern;
+                                let selected = pattern.and_then(|pattern| patterns.get(pattern)).map_or("Off", |name| name.as_str());
+                                egui::ComboBox::from_id_salt(("pattern", &identifier))
+                                    .selected_text(selected)
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut pattern, None, "Off");
+                                        for (index, name) in patterns.iter().enumerate() {
+                                            ui.selectable_value(&mut pattern, Some(index), name);
+                                        }
+                                    });
+                                if pattern != profile.pattern && profile.device.set_pattern(pattern).is_ok() {
+                                    profile.pattern = pattern;
+                                }
+                            });
+                        }
+
+                        // Temporal dithering between adjacent levels
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            let mut dither = device_settings.dither_interval_ms.is_some();
+                            if ui.checkbox(&mut dither, "Dither").changed() {
+                                self.settings.device_settings.entry(identifier.clone()).or_default().dither_interval_ms = dither.then_some(DEFAULT_DITHER_INTERVAL_MS);
+                                save_device_settings = true;
+                            }
+                            if let Some(mut dither_interval_ms) = device_settings.dither_interval_ms {
+                                let response = ui.add(
+                                    egui::DragValue::new(&mut dither_interval_ms)
+                                        .speed(1.0)
+                                        .range(20..=2000),
+                                );
+                                if response.changed() {
+                                    self.settings.device_settings.entry(identifier.clone()).or_default().dither_interval_ms = Some(dither_interval_ms);
+                                    save_device_settings = true;
+                                }
+                                ui.label("ms");
                             }
                         });
+
+                        // Write rate limit of devices with a coalescing backend
+                        if let Some(write_stats) = profile.device.write_stats() {
+                            ui.horizontal(|ui| {
+                                ui.add_space(24.0);
+                                ui.label("Min interval:");
+                                let mut min_write_interval_ms = device_settings.min_write_interval_ms;
+                                let response = ui.add(
+                                    egui::DragValue::new(&mut min_write_interval_ms)
+                                        .speed(1.0)
+                                        .range(0..=1000),
+                                );
+                                if response.changed() {
+                                    self.settings.device_settings.entry(identifier.clone()).or_default().min_write_interval_ms = min_write_interval_ms;
+                                    save_device_settings = true;
+                                }
+                                ui.label("ms");
+                                ui.colored_label(Color32::GRAY, format!("{} sent, {} dropped, {} failed", write_stats.written, write_stats.dropped, write_stats.failed));
+                            });
+                        }
+
+                        // Adapter assignment of GATT devices
+                        if profile.device.ble_address().is_some() && self.gatt_adapters.len() > 1 {
+                            ui.horizontal(|ui| {
+                                ui.add_space(24.0);
+                                ui.label("Adapter:");
+                                let mut adapter = device_settings.adapter.clone();
+                                if adapter_combo(ui, ("device_adapter", i), &mut adapter, &self.gatt_adapters) {
+                                    self.settings.device_settings.entry(identifier.clone()).or_default().adapter = adapter;
+                                    save_device_settings = true;
+                                    changed_adapter_device = Some(identifier.clone());
+                                }
+                            });
+                        }
+
+                        // Virtual device command log
+                        if let Some(command_log) = &profile.command_log {
+                            egui::CollapsingHeader::new("Received commands").id_salt(&identifier).show(ui, |ui| {
+                                let mut command_log = command_log.lock().expect("Could not lock");
+                                if ui.link("Clear").clicked() {
+                                    command_log.clear();
+                                }
+                                egui::ScrollArea::vertical().id_salt(&identifier).max_height(100.0).show(ui, |ui| {
+                                    let start = command_log.front().map(|c| c.timestamp);
+                                    for command in command_log.iter().rev() {
+                                        let elapsed = start.map(|s| command.timestamp - s).unwrap_or_default();
+                                        let level = command.level.map_or("-".into(), |l| l.to_string());
+                                        ui.colored_label(Color32::GRAY, format!("{:>8.3}s  [{}]  {}", elapsed.as_secs_f32(), level, command.payload_text()));
+                                    }
+                                });
+                            });
+                        }
+
+                        // Extra Lovense functions (rotation, air pump, presets)
+                        if profile.device.supports_lovense_commands() {
+                            egui::CollapsingHeader::new("Lovense controls").id_salt(("lovense", &identifier)).show(ui, |ui| {
+                                let controls = &mut profile.controls;
+                                let mut commands = Vec::new();
+
+                                // Unknown models get every control
+                                let capabilities = profile.device_type.as_ref().and_then(|d| d.capabilities());
+                                let has_rotation = capabilities.is_none_or(|c| c.rotation);
+                                let has_air_pump = capabilities.is_none_or(|c| c.air_pump);
+
+                                if has_rotation {
+                                    ui.horizontal(|ui| {
+                                        ui.label("Rotate:");
+                                        if ui.add(egui::Slider::new(&mut controls.rotation, 0..=LovenseCommand::MAX_ROTATE)).changed() {
+                                            commands.push(LovenseCommand::Rotate(controls.rotation));
+                                        }
+                                        if ui.small_button("Reverse").clicked() {
+                                            commands.push(LovenseCommand::RotateChange);
+                                        }
+                                    });
+                                }
+                                if has_air_pump {
+                                    ui.horizontal(|ui| {
+                                        ui.label("Air level:");
+                                        if ui.add(egui::Slider::new(&mut controls.air_level, 0..=LovenseCommand::MAX_AIR_LEVEL)).changed() {
+                                            commands.push(LovenseCommand::AirLevel(controls.air_level));
+                                        }
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.label("Pump:");
+                                        if ui.add(egui::Slider::new(&mut controls.pump, 0..=LovenseCommand::MAX_PUMP)).changed() {
+                                            commands.push(LovenseCommand::Pump(controls.pump));
+                                        }
+                                    });
+                                }
+                                ui.horizontal(|ui| {
+                                    ui.label("Preset:");
+                                    if ui.add(egui::Slider::new(&mut controls.preset, 0..=LovenseCommand::MAX_PRESET)).changed() {
+                                        commands.push(LovenseCommand::Preset(controls.preset));
+                                    }
+                                });
+                                ui.horizontal(|ui| {
+                                    if ui.button("Battery").clicked() {
+                                        commands.push(LovenseCommand::Battery);
+                                    }
+                                    if ui.button("Power off").clicked() {
+                                        commands.push(LovenseCommand::PowerOff);
+                                    }
+                                });
+
+                                for command in commands {
+                                    _ = profile.device.send_lovense_command(command);
+                                }
+                            });
+                        }
+                    }
                 });
 
-                match (&self.adapter_status, self.selected_device) {
-                    (_, 0) => {}
-                    (Some(AdapterStatus::NotConnected), _) => { ui.colored_label(Color32::RED, "Not connected"); }
-                    (Some(AdapterStatus::Connecting(_)), _) => { ui.colored_label(Color32::ORANGE, "Connecting..."); }
-                    (Some(AdapterStatus::Connected(_)), _) => { ui.colored_label(Color32::GREEN, "Connected!"); }
-                    _ => {}
+                if let Some((index, enabled)) = toggled_device {
+                    self.set_device_enabled(index, enabled);
+                }
+                if save_device_settings {
+                    self.settings.save().unwrap();
+                }
+                if let Some(identifier) = changed_adapter_device {
+                    self.reconnect_gatt_devices(Some(&identifier));
+                }
+                if let Some(wizard) = start_calibration {
+                    self.calibration.replace(wizard);
                 }
 
                 ui.add_space(10.0);
@@ -412,7 +1271,21 @@ impl eframe::App for AppContext {
                                 .desired_width(f32::INFINITY)
                         );
                         if response.changed() {
-                            self.osc_server.set_pattern(WildMatch::new(self.settings.osc_path.as_str()));
+                            self.update_osc_channels();
+                            self.settings.save().unwrap();
+                        }
+
+                        ui.add_space(10.0);
+
+                        // OSC address selecting the built-in patterns
+                        ui.label("Pattern OSC Address:");
+                        let response = ui.add(
+                            egui::TextEdit::singleline(&mut self.settings.osc_pattern_path)
+                                .hint_text("Off")
+                                .desired_width(f32::INFINITY)
+                        );
+                        if response.changed() {
+                            self.update_osc_channels();
                             self.settings.save().unwrap();
                         }
 
@@ -424,12 +1297,288 @@ impl eframe::App for AppContext {
                             ui.colored_label(Color32::CYAN, format!("{:.3}", self.osc_value.value));
                         });
                         if !self.osc_value.address.is_empty() {
-                            ui.colored_label(Color32::GRAY, format!("{}", self.osc_value.address));
+                            ui.colored_label(Color32::GRAY, &self.osc_value.address);
                         }
                         ui.add_space(10.0);
                     }
                 }
 
+                // Input routing
+                if self.found_devices.len() > 1 {
+                    if ui.link(if self.show_routing { "Hide input routing" } else { "Show input routing" }).clicked() {
+                        self.show_routing = !self.show_routing;
+                    }
+
+                    ui.add_space(10.0);
+                }
+
+                if self.show_routing {
+                    let mut remove_route = None;
+                    let mut routes_changed = false;
+                    for (i, route) in self.settings.routes.iter_mut().enumerate() {
+                        let device_motors = self.found_devices
+                            .iter()
+                            .find(|profile| profile.device.identifier() == route.device)
+                            .map_or(1, |profile| profile.motors());
+                        ui.horizontal(|ui| {
+                            egui::ComboBox::from_id_salt(("route_source", i))
+                                .width(70.0)
+                                .selected_text(route.source.label())
+                                .show_ui(ui, |ui| {
+                                    let osc_source = match &route.source {
+                                        InputSource::Osc(pattern) => InputSource::Osc(pattern.clone()),
+                                        _ => InputSource::Osc(self.settings.osc_path.clone()),
+                                    };
+                                    for source in [InputSource::Manual, InputSource::ManualSecondary, InputSource::Remote, InputSource::RemoteSecondary, osc_source] {
+                                        let label = source.label();
+                                        if ui.selectable_value(&mut route.source, source, label).changed() {
+                                            routes_changed = true;
+                                        }
+                                    }
+                                });
+
+                            ui.label("->");
+
+                            let device_name = self.found_devices
+                                .iter()
+                                .find(|profile| profile.device.identifier() == route.device)
+                                .map_or(route.device.clone(), |profile| profile.name());
+                            egui::ComboBox::from_id_salt(("route_device", i))
+                                .width(120.0)
+                                .selected_text(device_name)
+                                .show_ui(ui, |ui| {
+                                    for profile in &self.found_devices {
+                                        if ui.selectable_value(&mut route.device, profile.device.identifier(), profile.name()).changed() {
+                                            routes_changed = true;
+                                        }
+                                    }
+                                });
+
+                            if ui.small_button("x").clicked() {
+                                remove_route = Some(i);
+                            }
+                        });
+
+                        // Target motor of dual-motor devices
+                        if device_motors > 1 {
+                            ui.horizontal(|ui| {
+                                ui.label("Motor:");
+                                if ui.radio_value(&mut route.motor, None, "All").changed() {
+                                    routes_changed = true;
+                                }
+                                for motor in 1..=device_motors {
+                                    if ui.radio_value(&mut route.motor, Some(motor), motor.to_string()).changed() {
+                                        routes_changed = true;
+                                    }
+                                }
+                            });
+                        }
+
+                        if let InputSource::Osc(pattern) = &mut route.source {
+                            ui.horizontal(|ui| {
+                                ui.label("OSC Address:");
+                                if ui.text_edit_singleline(pattern).changed() {
+                                    routes_changed = true;
+                                }
+                            });
+                        }
+                    }
+
+                    if let Some(index) = remove_route {
+                        self.settings.routes.remove(index);
+                        routes_changed = true;
+                    }
+
+                    if ui.button("Add route").clicked() {
+                        self.settings.routes.push(InputRoute {
+                            source: InputSource::Manual,
+                            device: "generic".into(),
+                            motor: None,
+                        });
+                        routes_changed = true;
+                    }
+
+                    if routes_changed {
+                        self.update_osc_channels();
+                        self.settings.save().unwrap();
+                    }
+
+                    ui.add_space(10.0);
+                }
+
+                // Bluetooth adapter selection
+                let adv_adapters = self.generic_service.adapters();
+                if self.gatt_adapters.len() > 1 || adv_adapters.len() > 1 {
+                    if ui.link(if self.show_adapter_settings { "Hide adapter settings" } else { "Show adapter settings" }).clicked() {
+                        self.show_adapter_settings = !self.show_adapter_settings;
+                    }
+
+                    ui.add_space(10.0);
+                }
+
+                if self.show_adapter_settings {
+                    ui.horizontal(|ui| {
+                        ui.label("GATT Adapter:");
+                        if adapter_combo(ui, "gatt_adapter", &mut self.settings.gatt_adapter, &self.gatt_adapters) {
+                            self.settings.save().unwrap();
+                            self.reconnect_gatt_devices(None);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("ADV Adapter:");
+                        if adapter_combo(ui, "adv_adapter", &mut self.settings.adv_adapter, &adv_adapters) {
+                            self.settings.save().unwrap();
+                            self.generic_service.set_adapter(self.settings.adv_adapter.clone());
+                        }
+                    });
+
+                    ui.add_space(10.0);
+                }
+
+                // Advertisement based toys and their radio settings
+                if ui.link(if self.show_generic_settings { "Hide generic toy settings" } else { "Show generic toy settings" }).clicked() {
+                    self.show_generic_settings = !self.show_generic_settings;
+                }
+                ui.add_space(10.0);
+
+                if self.show_generic_settings {
+                    let previous = self.settings.generic_toys.clone();
+                    let mut removed_toy = None;
+                    for (i, toy) in self.settings.generic_toys.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.add(egui::TextEdit::singleline(&mut toy.name).desired_width(140.0));
+                            ui.colored_label(Color32::GRAY, &toy.identifier);
+                            if ui.small_button("Remove").clicked() {
+                                removed_toy = Some(i);
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            ui.label("Address:");
+                            for byte in &mut toy.address {
+                                ui.add(egui::DragValue::new(byte).hexadecimal(2, false, true));
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.add_space(24.0);
+                            ui.label("Company ID:");
+                            ui.add(egui::DragValue::new(&mut toy.company_id).hexadecimal(4, false, true));
+                            ui.label("Protocol:");
+                            egui::ComboBox::from_id_salt(("adv_protocol", &toy.identifier))
+                                .width(140.0)
+                                .selected_text(protocol(&toy.protocol).name.as_str())
+                                .show_ui(ui, |ui| {
+                                    for protocol in adv_protocol::protocols() {
+                                        ui.selectable_value(&mut toy.protocol, protocol.id.clone(), protocol.name.as_str());
+                                    }
+                                });
+                        });
+                    }
+                    if let Some(index) = removed_toy {
+                        self.settings.generic_toys.remove(index);
+                    }
+                    if ui.link(if self.show_learn { "Stop learning" } else { "Learn from a stock remote" }).clicked() {
+                        self.show_learn = !self.show_learn;
+                        self.generic_service.set_learning(self.show_learn);
+                    }
+                    if ui.button("Add generic toy").clicked() {
+                        let identifier = GenericToy::next_identifier(&self.settings.generic_toys);
+                        self.settings.generic_toys.push(GenericToy {
+                            name: format!("Generic Device ({})", identifier),
+                            identifier,
+                            ..Default::default()
+                        });
+                    }
+
+                    let mut options_changed = false;
+                    ui.horizontal(|ui| {
+                        ui.label("Advertising interval:");
+                        options_changed |= ui.add(
+                            egui::DragValue::new(&mut self.settings.adv_interval_ms)
+                                .speed(1.0)
+                                .range(MIN_ADV_INTERVAL_MS..=MAX_ADV_INTERVAL_MS),
+                        ).changed();
+                        ui.label("ms");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("TX power:");
+                        let mut adapter_maximum = self.settings.adv_tx_power.is_none();
+                        if ui.checkbox(&mut adapter_maximum, "Adapter maximum").changed() {
+                            self.settings.adv_tx_power = (!adapter_maximum).then_some(0);
+                            options_changed = true;
+                        }
+                        if let Some(tx_power) = &mut self.settings.adv_tx_power {
+                            options_changed |= ui.add(egui::DragValue::new(tx_power).speed(1.0).range(-127..=20)).changed();
+                            ui.label("dBm");
+                        }
+                    });
+
+                    if options_changed {
+                        self.generic_service.set_options(self.settings.adv_options());
+                    }
+                    if self.settings.generic_toys != previous {
+                        self.reload_generic_devices(&previous);
+                    }
+                    if options_changed || self.settings.generic_toys != previous {
+                        self.settings.save().unwrap();
+                    }
+
+                    ui.add_space(10.0);
+                }
+
+                // Every advertising device, for toys that are not recognized
+                if ui.link(if self.show_explorer { "Hide BLE explorer" } else { "Show BLE explorer" }).clicked() {
+                    self.show_explorer = !self.show_explorer;
+                    self.update_diagnostics();
+                }
+                ui.add_space(10.0);
+
+                // Keepalive settings for toys that stop on their own
+                if ui.link(if self.show_keepalive_settings { "Hide keepalive settings" } else { "Show keepalive settings" }).clicked() {
+                    self.show_keepalive_settings = !self.show_keepalive_settings;
+                }
+                ui.add_space(10.0);
+
+                if self.show_keepalive_settings {
+                    let mut protocols = self.found_devices.iter().filter_map(|profile| profile.device.protocol()).collect::<Vec<String>>();
+                    protocols.sort();
+                    protocols.dedup();
+
+                    let mut keepalive_changed = false;
+                    for protocol in protocols {
+                        ui.horizontal(|ui| {
+                            let mut keepalive_ms = self.settings.keepalive_ms.get(&protocol).copied().unwrap_or(0);
+                            ui.label(format!("{} keepalive:", protocol));
+                            if ui.add(egui::DragValue::new(&mut keepalive_ms).speed(10.0).range(0..=60000)).changed() {
+                                self.settings.keepalive_ms.insert(protocol.clone(), keepalive_ms);
+                                keepalive_changed = true;
+                            }
+                            ui.label("ms");
+                            if keepalive_ms == 0 {
+                                ui.label("(off)");
+                            }
+                        });
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.label("Stop advertising after idle:");
+                        if ui.add(egui::DragValue::new(&mut self.settings.adv_idle_stop_secs).speed(1.0).range(0..=3600)).changed() {
+                            self.generic_service.set_idle_stop(self.settings.adv_idle_stop());
+                            keepalive_changed = true;
+                        }
+                        ui.label("s");
+                        if self.settings.adv_idle_stop_secs == 0 {
+                            ui.label("(never)");
+                        }
+                    });
+
+                    if keepalive_changed {
+                        self.settings.save().unwrap();
+                    }
+
+                    ui.add_space(10.0);
+                }
+
                 // Remote control settings
                 let mut save_settings = false;
                 if let ControlMode::Remote(mode) = &mut self.settings.mode {
@@ -437,7 +1586,7 @@ impl eframe::App for AppContext {
                     ui.add_space(10.0);
 
                     if ui.radio_value(mode, RemoteMode::Sender, "Sender").clicked() {
-                        self.remote_receiver.as_mut().and_then(|receiver| Some(receiver.stop()));
+                        self.remote_receiver.as_mut().map(|receiver| receiver.stop());
                         save_settings = true;
                     }
 
@@ -448,27 +1597,26 @@ impl eframe::App for AppContext {
                         ui.text_edit_singleline(&mut self.remote_sender.code);
                         ui.add_space(4.0);
                         ui.horizontal(|ui| {
-                            if ui.button("Connect").clicked() {
-                                if let Ok(decoded) = base64::prelude::BASE64_STANDARD
+                            if ui.button("Connect").clicked()
+                                && let Ok(decoded) = base64::prelude::BASE64_STANDARD
                                     .decode(self.remote_sender.code.as_bytes())
                                     .map_err(anyhow::Error::from)
                                     .and_then(|decoded| String::from_utf8(decoded).map_err(anyhow::Error::from)) {
-                                    let split = decoded.split("|").collect::<Vec<&str>>();
-                                    if split.len() != 2 {
-                                        return;
-                                    }
+                                let split = decoded.split("|").collect::<Vec<&str>>();
+                                if split.len() != 2 {
+                                    return;
+                                }
 
-                                    let url = split[0];
-                                    let pairing_code = split[1];
-
-                                    if let Ok(url) = Url::parse(url) {
-                                        match self.remote_sender.connect_to(url, pairing_code) {
-                                            Ok(_) => {
-                                                self.sender_state = RemoteSenderState::Connected;
-                                            }
-                                            Err(error) => {
-                                                self.sender_state = RemoteSenderState::Error(format!("{}", error));
-                                            }
+                                let url = split[0];
+                                let pairing_code = split[1];
+
+                                if let Ok(url) = Url::parse(url) {
+                                    match self.remote_sender.connect_to(url, pairing_code) {
+                                        Ok(_) => {
+                                            self.sender_state = RemoteSenderState::Connected;
+                                        }
+                                        Err(error) => {
+                                            self.sender_state = RemoteSenderState::Error(format!("{}", error));
                                         }
                                     }
                                 }
@@ -492,7 +1640,7 @@ impl eframe::App for AppContext {
                     ui.add_space(10.0);
 
                     if ui.radio_value(mode, RemoteMode::Receiver, "Receiver").clicked() {
-                        self.remote_receiver.as_mut().and_then(|receiver| Some(receiver.start()));
+                        self.remote_receiver.as_mut().map(|receiver| receiver.start());
                         self.remote_sender.disconnect();
                         self.sender_state = RemoteSenderState::NotConnected;
                         save_settings = true;
@@ -506,7 +1654,7 @@ impl eframe::App for AppContext {
                             let url = self.sender_url.clone().unwrap();
                             let pairing_code = self.sender_pairing_code.clone().unwrap();
                             let code = url + "|" + pairing_code.as_str();
-                            let code = base64::prelude::BASE64_STANDARD.encode(&code.as_bytes());
+                            let code = base64::prelude::BASE64_STANDARD.encode(code.as_bytes());
                             let mut clipbpard = arboard::Clipboard::new().unwrap();
                             clipbpard.set_text(code.as_str()).unwrap();
                             println!("{}", code);
@@ -527,7 +1675,7 @@ impl eframe::App for AppContext {
                     });
 
                     if can_retry && ui.button("Retry").clicked() {
-                        self.remote_receiver.as_mut().and_then(|receiver| Some(receiver.start()));
+                        self.remote_receiver.as_mut().map(|receiver| receiver.start());
                     }
                 }
 
@@ -537,51 +1685,135 @@ impl eframe::App for AppContext {
             });
         });
 
-        if self.intensity != self.last_intensity || self.last_max_intensity_perc != self.settings.max_intensity_percent {
+        if self.intensity != self.last_intensity {
             self.last_intensity = self.intensity;
-            self.last_max_intensity_perc = self.settings.max_intensity_percent;
-
-            let speed_scale = self.settings.max_intensity_percent as f32 / 100.0;
-            let intensity = (self.intensity as f32 * speed_scale) as u8;
 
             if let ControlMode::Remote(RemoteMode::Sender) = self.settings.mode {
-                _ = self.remote_sender.send_speed(self.intensity as f32 / 20.0);
+                _ = self.remote_sender.send_speed(0, self.intensity as f32 / 20.0);
             }
+        }
 
-            if self.settings.mode != ControlMode::Remote(RemoteMode::Sender) || self.settings.remote_sync_local {
-                _ = match self.selected_device {
-                    0 => self.generic_service.send_speed(intensity),
-                    _ => self.gatt_service.send_speed(intensity),
-                };
+        if self.intensity_secondary != self.last_intensity_secondary {
+            self.last_intensity_secondary = self.intensity_secondary;
+
+            if let ControlMode::Remote(RemoteMode::Sender) = self.settings.mode {
+                _ = self.remote_sender.send_speed(1, self.intensity_secondary as f32 / 20.0);
             }
         }
 
+        if self.show_explorer {
+            self.draw_explorer(ctx);
+        }
+        if self.show_learn {
+            self.draw_learn(ctx);
+        }
+        if self.calibration.is_some() {
+            self.draw_calibration(ctx);
+        }
+
+        self.update_outputs();
+
         ctx.request_repaint_after(Duration::from_millis(1000 / 30));
     }
 }
 
-enum DeviceProfile {
-    GenericDevice,
-    GattDevice(BluetoothGattDevice),
+/// Adapter selector with a "Default" entry, returns whether the selection changed
+fn adapter_combo(ui: &mut egui::Ui, id_salt: impl std::hash::Hash, selected: &mut Option<String>, adapters: &[String]) -> bool {
+    let mut changed = false;
+    egui::ComboBox::from_id_salt(id_salt)
+        .width(160.0)
+        .selected_text(selected.as_deref().unwrap_or("Default"))
+        .show_ui(ui, |ui| {
+            changed |= ui.selectable_value(selected, None, "Default").changed();
+            for adapter in adapters {
+                changed |= ui.selectable_value(selected, Some(adapter.clone()), adapter).changed();
+            }
+        });
+
+    changed
+}
+
+struct DeviceProfile {
+    device: Box<dyn OutputDevice>,
+    command_log: Option<CommandLog>,
+    status: DeviceStatus,
+    controls: DeviceControls,
+    battery: Option<u8>,
+    device_type: Option<LovenseDeviceType>,
+    error: Option<String>, // why the last connection attempt or command failed
+    alias: Option<String>,
+    dither: HashMap<u8, Dither>, // by motor
+    pattern: Option<usize>, // built-in program played instead of plain levels
 }
 
 impl DeviceProfile {
-    fn get_name(&self) -> String {
-        match self {
-            DeviceProfile::GenericDevice => "Generic Device".into(),
-            DeviceProfile::GattDevice(device) => {
-                device.device_name.clone().unwrap_or(device.device_address.clone())
-            }
+    fn new(device: impl OutputDevice + 'static) -> Self {
+        Self {
+            device: Box::new(device),
+            command_log: None,
+            status: DeviceStatus::NotConnected,
+            controls: DeviceControls::default(),
+            battery: None,
+            device_type: None,
+            error: None,
+            alias: None,
+            dither: HashMap::new(),
+            pattern: None,
         }
     }
+
+    fn new_virtual(protocol: VirtualProtocol) -> Self {
+        let command_log = VirtualDevice::new_log();
+        Self {
+            device: Box::new(VirtualDevice::new(protocol, command_log.clone())),
+            command_log: Some(command_log),
+            status: DeviceStatus::NotConnected,
+            controls: DeviceControls::default(),
+            battery: None,
+            device_type: None,
+            error: None,
+            alias: None,
+            dither: HashMap::new(),
+            pattern: None,
+        }
+    }
+
+    fn motors(&self) -> u8 {
+        self.device_type
+            .as_ref()
+            .and_then(|device_type| device_type.capabilities())
+            .map_or(self.device.motors(), |capabilities| capabilities.motors)
+    }
+
+    /// Alias assigned by the user, otherwise the default name
+    fn name(&self) -> String {
+        self.alias.clone().unwrap_or_else(|| self.default_name())
+    }
+
+    /// Detected model name if the toy identified itself, otherwise the advertised name
+    fn default_name(&self) -> String {
+        match &self.device_type {
+            Some(device_type) => format!("Lovense {}", device_type.model_name()),
+            None => self.device.name(),
+        }
+    }
+}
+
+/// Last values of the extra device controls shown in the GUI
+#[derive(Debug, Default)]
+struct DeviceControls {
+    rotation: u8,
+    air_level: u8,
+    pump: u8,
+    preset: u8,
 }
 
-#[allow(unused)]
 #[derive(Debug)]
-enum AdapterStatus {
+enum DeviceStatus {
     NotConnected,
-    Connecting(String),
-    Connected(String),
+    Connecting,
+    Reconnecting(u32), // attempt
+    Connected,
 }
 
 enum RemoteSenderState {
@@ -0,0 +1,213 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::bluetooth::adv_protocol::{self, protocol};
+use crate::bluetooth::btsnoop;
+use crate::bluetooth::generic::{AdvOutput, BleUtil, WhiteningScheme};
+
+const ENCODED_LENGTH: usize = 11;
+
+/// Fields of a generic toy advertisement, the inverse of a protocol's encoder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedPayload {
+    pub protocol: Option<String>, // identifier of the protocol whose header matched
+    pub header: [u8; 3],
+    pub expected_header: [u8; 3],
+    pub address: [u8; 5],
+    pub raw_command: [u8; 3], // last three bytes as advertised, used by `Command::Raw`
+    pub byte_command: Option<u8>, // de-whitened command byte, only if its CRC matches
+    pub crc: u16, // 0 for protocols without CRC
+    pub expected_crc: u16, // CRC of the address and the de-whitened command byte
+    pub output: Option<AdvOutput>, // speed level or program of the protocol, `None` for unknown commands
+}
+
+impl DecodedPayload {
+    pub fn header_valid(&self) -> bool {
+        self.header == self.expected_header
+    }
+}
+
+/// Decodes a manufacturer data payload of any known protocol, with or without its leading flags.
+///
+/// Payloads no protocol recognizes are decoded with the first one and have an invalid header.
+pub fn decode(payload: &[u8]) -> anyhow::Result<DecodedPayload> {
+    let mut fallback = None;
+    for protocol in adv_protocol::protocols() {
+        match protocol.decode(payload) {
+            Ok(decoded) if decoded.header_valid() => return Ok(decoded),
+            result => _ = fallback.get_or_insert(result),
+        }
+    }
+    fallback.unwrap_or_else(|| Err(anyhow::anyhow!("No ADV protocols")))
+}
+
+/// Decodes the payload of a whitened protocol without its flags
+pub fn decode_whitened(scheme: &WhiteningScheme, payload: &[u8]) -> anyhow::Result<DecodedPayload> {
+    let payload: [u8; ENCODED_LENGTH] = payload
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected {} bytes after the flags, got {}", ENCODED_LENGTH, payload.len()))?;
+
+    // Whitening only XORs a keystream, so applying it again restores the plain bytes
+    let mask = whitening_mask(scheme);
+    let plain: [u8; ENCODED_LENGTH] = std::array::from_fn(|i| payload[i] ^ mask[i]);
+
+    let header = [0, 1, 2].map(|i| BleUtil::invert_8(plain[i]));
+    let address = [0, 1, 2, 3, 4].map(|i| BleUtil::invert_8(plain[7 - i]));
+    let crc = u16::from_le_bytes([plain[9], plain[10]]);
+    let expected_crc = BleUtil::check_crc16(&address, &[plain[8]]);
+
+    Ok(DecodedPayload {
+        protocol: None,
+        header,
+        expected_header: scheme.header,
+        address,
+        raw_command: [payload[8], payload[9], payload[10]],
+        byte_command: (crc == expected_crc).then_some(plain[8]),
+        crc,
+        expected_crc,
+        output: None,
+    })
+}
+
+/// Decodes the payload of a plain protocol without its flags, byte commands are followed by two zero bytes
+pub fn decode_plain(expected_header: &[u8; 3], payload: &[u8]) -> anyhow::Result<DecodedPayload> {
+    let payload: [u8; ENCODED_LENGTH] = payload
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected {} bytes after the flags, got {}", ENCODED_LENGTH, payload.len()))?;
+    let raw_command = [payload[8], payload[9], payload[10]];
+
+    Ok(DecodedPayload {
+        protocol: None,
+        header: [payload[0], payload[1], payload[2]],
+        expected_header: *expected_header,
+        address: [payload[3], payload[4], payload[5], payload[6], payload[7]],
+        raw_command,
+        byte_command: (raw_command[1..] == [0, 0]).then_some(raw_command[0]),
+        crc: 0,
+        expected_crc: 0,
+        output: None,
+    })
+}
+
+/// Parses hex with optional `0x` prefix and `:`, `-` or whitespace separators
+pub fn parse_hex(text: &str) -> anyhow::Result<Vec<u8>> {
+    let text = text.trim();
+    let text = text.strip_prefix("0x").unwrap_or(text);
+    let digits = text.chars().filter(|c| !matches!(c, ':' | '-' | ' ')).collect::<String>();
+    Ok(hex::decode(digits)?)
+}
+
+/// `decode-adv` command, prints the fields of every hex payload argument, or of the payloads in a btsnoop log
+pub fn run_cli(payloads: &[String]) -> anyhow::Result<()> {
+    if payloads.is_empty() {
+        anyhow::bail!("Usage: vibe-link decode-adv <hex payload | btsnoop log>...");
+    }
+
+    for payload in payloads {
+        if Path::new(payload).is_file() {
+            let log = std::fs::read(payload)?;
+            for (company_id, data) in btsnoop::manufacturer_data(&log)? {
+                if let Ok(decoded) = decode(&data) && decoded.header_valid() {
+                    println!("{} (company ID 0x{:04X})", hex::encode(&data), company_id);
+                    println!("{}", decoded);
+                }
+            }
+            continue;
+        }
+
+        println!("{}", payload);
+        match parse_hex(payload).and_then(|bytes| decode(&bytes)) {
+            Ok(decoded) => println!("{}", decoded),
+            Err(error) => println!("  Error: {}", error),
+        }
+    }
+
+    Ok(())
+}
+
+impl Display for DecodedPayload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.protocol {
+            Some(id) => writeln!(f, "  Protocol: {}", protocol(id).name)?,
+            None => writeln!(f, "  Protocol: unknown")?,
+        }
+        let valid = |valid| if valid { "ok" } else { "mismatch" };
+        writeln!(f, "  Header:  {} ({})", hex::encode(self.header), valid(self.header_valid()))?;
+        writeln!(f, "  Address: {}", hex::encode(self.address))?;
+        writeln!(f, "  Raw command: {}", hex::encode(self.raw_command))?;
+        match self.byte_command {
+            Some(command) => writeln!(f, "  Byte command: {:02x} (CRC {:04x} ok)", command, self.crc)?,
+            None => writeln!(f, "  Byte command: none (CRC {:04x}, expected {:04x})", self.crc, self.expected_crc)?,
+        }
+        match (&self.protocol, self.output) {
+            (Some(id), Some(output)) => write!(f, "  Meaning: {}", protocol(id).output_name(output)),
+            _ => write!(f, "  Meaning: unknown"),
+        }
+    }
+}
+
+/// Keystream both whitening passes of the encoder XOR into the 11 advertised bytes
+fn whitening_mask(scheme: &WhiteningScheme) -> [u8; ENCODED_LENGTH] {
+    let mut ctx_25 = [0u8; 7];
+    let mut ctx_3f = [0u8; 7];
+    BleUtil::whitening_init(scheme.seeds[0], &mut ctx_25);
+    BleUtil::whitening_init(scheme.seeds[1], &mut ctx_3f);
+
+    let zeros = [0u8; 0x1a];
+    let mut mask_25 = [0u8; 0x1a];
+    let mut mask_3f = [0u8; 0x1a];
+    BleUtil::whitening_encode(&zeros, zeros.len(), &mut ctx_25, 0x00, &mut mask_25);
+    BleUtil::whitening_encode(&zeros, 8, &mut ctx_3f, 0x12, &mut mask_3f);
+
+    std::array::from_fn(|i| mask_25[0x0f + i] ^ mask_3f[0x0f + i])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bluetooth::adv_protocol::CLASSIC_PROTOCOL;
+    use crate::bluetooth::generic::{Command, DEFAULT_ADDRESS};
+    use super::*;
+
+    #[test]
+    fn decodes_sample_payload() {
+        let decoded = decode(&parse_hex("0201066db643ce97fe427ce60000").unwrap()).unwrap();
+        assert!(decoded.header_valid());
+        assert_eq!(decoded.address, DEFAULT_ADDRESS);
+        assert_eq!(decoded.raw_command, [0xE6, 0x00, 0x00]);
+        assert_eq!(decoded.protocol.as_deref(), Some(CLASSIC_PROTOCOL));
+        assert_eq!(decoded.output, Some(AdvOutput::Speed(7)));
+    }
+
+    #[test]
+    fn round_trips_encoded_commands() {
+        let address = [0x01, 0x23, 0x45, 0x67, 0x89];
+        let classic = WhiteningScheme { seeds: [0x25, 0x3f], header: [0x71, 0x0f, 0x55] };
+        let decoded = decode(&BleUtil::get_ble_command(&classic, &address, Command::Byte(0x42))).unwrap();
+        assert!(decoded.header_valid());
+        assert_eq!(decoded.address, address);
+        assert_eq!(decoded.byte_command, Some(0x42));
+
+        // Raw speed codes carry no valid CRC
+        let payload = protocol(CLASSIC_PROTOCOL).payload(&address, AdvOutput::Speed(3));
+        let decoded = decode(&payload).unwrap();
+        assert_eq!(decoded.byte_command, None);
+        assert_eq!(decoded.output, Some(AdvOutput::Speed(3)));
+
+        assert!(decode(&payload[..10]).is_err());
+        assert_eq!(parse_hex("0x02:01:06").unwrap(), vec![0x02, 0x01, 0x06]);
+    }
+
+    #[test]
+    fn round_trips_every_program() {
+        let classic = protocol(CLASSIC_PROTOCOL);
+        for address in [DEFAULT_ADDRESS, [0x01, 0x23, 0x45, 0x67, 0x89]] {
+            for (index, pattern) in classic.patterns.iter().enumerate() {
+                let decoded = decode(&classic.payload(&address, AdvOutput::Pattern(index))).unwrap();
+                assert_eq!(decoded.address, address);
+                assert_eq!(decoded.output, Some(AdvOutput::Pattern(index)), "{}", pattern.name);
+
+                // Programs are byte commands, so their CRC matches for every address
+                assert!(decoded.byte_command.is_some(), "{}", pattern.name);
+            }
+        }
+    }
+}
@@ -2,17 +2,20 @@
 pub mod ble_adv {
     use std::collections::{BTreeMap, HashMap};
     use std::io::Write;
-    use std::time::Duration;
+    use std::sync::mpsc::Sender;
     use bluer::adv::{Advertisement, AdvertisementHandle, Type};
-    use bluer::{Adapter, Session};
+    use bluer::{Adapter, AdapterEvent, DiscoveryFilter, DiscoveryTransport, Session};
     use serialport::SerialPort;
-    use crate::bluetooth::generic::BleAdvertiser;
+    use tokio::task::JoinHandle;
+    use tokio_stream::StreamExt;
+    use crate::bluetooth::generic::{AdvOptions, BleAdvertiser};
 
     pub struct BleAdvertiserLinux {
         session: Option<Session>,
         adapter: Option<Adapter>,
-        adv_handle: Option<AdvertisementHandle>,
+        adv_handles: HashMap<String, AdvertisementHandle>, // by toy identifier
         max_tx_power: i16,
+        scan_task: Option<JoinHandle<()>>, // discovery session of the learn mode
 
         serial_port: Option<Box<dyn SerialPort>>,
         speed_dict: HashMap<u8, u8>,
@@ -23,8 +26,9 @@ pub mod ble_adv {
             Self {
                 session: None,
                 adapter: None,
-                adv_handle: None,
+                adv_handles: HashMap::new(),
                 max_tx_power: 20,
+                scan_task: None,
 
                 serial_port: None,
                 speed_dict: HashMap::new(),
@@ -33,7 +37,7 @@ pub mod ble_adv {
     }
 
     impl BleAdvertiser for BleAdvertiserLinux {
-        async fn init(&mut self) -> anyhow::Result<()> {
+        async fn init(&mut self, adapter: Option<&str>) -> anyhow::Result<()> {
             self.speed_dict.insert(0xE5, b'0');
             self.speed_dict.insert(0xF4, b'1');
             self.speed_dict.insert(0xF7, b'2');
@@ -45,13 +49,20 @@ pub mod ble_adv {
 
             // return Ok(());
 
+            self.adv_handles.clear();
+            self.stop_scan().await?;
+            drop(self.adapter.take());
             drop(self.session.take());
 
             let session = Session::new().await?;
-            let adapter = match  session.default_adapter().await {
-                Ok(adapter) => adapter,
-                Err(error) => {
-                    return Err(anyhow::anyhow!("Error getting default adapter: {}", error));
+            let adapter = match adapter {
+                Some(name) => session.adapter(name)
+                    .map_err(|error| anyhow::anyhow!("Error getting adapter {}: {}", name, error))?,
+                None => match session.default_adapter().await {
+                    Ok(adapter) => adapter,
+                    Err(error) => {
+                        return Err(anyhow::anyhow!("Error getting default adapter: {}", error));
+                    },
                 },
             };
 
@@ -66,7 +77,59 @@ pub mod ble_adv {
             Ok(())
         }
 
-        async fn send(&mut self, mfr_id: u16, data: &[u8]) -> anyhow::Result<()> {
+        async fn adapters(&self) -> anyhow::Result<Vec<String>> {
+            match &self.session {
+                Some(session) => Ok(session.adapter_names().await?),
+                None => Ok(Vec::new()),
+            }
+        }
+
+        async fn stop(&mut self, identifier: &str) -> anyhow::Result<()> {
+            drop(self.adv_handles.remove(identifier));
+            Ok(())
+        }
+
+        async fn scan(&mut self, company_id: u16, scan_tx: Sender<(u16, Vec<u8>)>) -> anyhow::Result<()> {
+            self.stop_scan().await?;
+            let Some(adapter) = self.adapter.clone() else {
+                anyhow::bail!("No advertising adapter");
+            };
+
+            // Without duplicate data BlueZ only reports manufacturer data that changed
+            adapter.set_discovery_filter(DiscoveryFilter {
+                transport: DiscoveryTransport::Le,
+                duplicate_data: true,
+                ..Default::default()
+            }).await?;
+            let mut events = adapter.discover_devices_with_changes().await?;
+
+            self.scan_task.replace(tokio::spawn(async move {
+                while let Some(event) = events.next().await {
+                    let AdapterEvent::DeviceAdded(address) = event else {
+                        continue;
+                    };
+                    let Ok(device) = adapter.device(address) else {
+                        continue;
+                    };
+                    if let Ok(Some(manufacturer_data)) = device.manufacturer_data().await
+                        && let Some(data) = manufacturer_data.get(&company_id)
+                        && scan_tx.send((company_id, data.clone())).is_err() {
+                        break;
+                    }
+                }
+            }));
+
+            Ok(())
+        }
+
+        async fn stop_scan(&mut self) -> anyhow::Result<()> {
+            if let Some(scan_task) = self.scan_task.take() {
+                scan_task.abort();
+            }
+            Ok(())
+        }
+
+        async fn send(&mut self, identifier: &str, mfr_id: u16, data: &[u8], options: AdvOptions) -> anyhow::Result<()> {
             if let Some(port) = &mut self.serial_port {
                 let speed = self.speed_dict[&data[11]];
                 port.write_all(&[speed])?;
@@ -81,18 +144,18 @@ pub mod ble_adv {
                 manufacturer_data,
                 duration: None,
                 timeout: None,
-                min_interval: Some(Duration::from_millis(20)),
-                max_interval: Some(Duration::from_millis(20)),
-                tx_power: Some(self.max_tx_power),
+                min_interval: Some(options.interval),
+                max_interval: Some(options.interval),
+                tx_power: Some(options.tx_power.map_or(self.max_tx_power, |tx_power| tx_power.min(self.max_tx_power))),
                 ..Default::default()
             };
 
-            if let Some(handle) = self.adv_handle.take() {
+            if let Some(handle) = self.adv_handles.remove(identifier) {
                 drop(handle);
             }
 
             if let Some(adapter) = &self.adapter {
-                self.adv_handle.replace(adapter.advertise(advertisement).await?);
+                self.adv_handles.insert(identifier.into(), adapter.advertise(advertisement).await?);
             }
 
             Ok(())
@@ -0,0 +1,403 @@
+use std::fmt::Debug;
+use std::path::PathBuf;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use crate::bluetooth::adv_decoder::{decode_plain, decode_whitened, parse_hex, DecodedPayload};
+use crate::bluetooth::generic::{AdvOutput, BleUtil, Command, WhiteningScheme};
+
+pub const CLASSIC_PROTOCOL: &str = "classic";
+
+const BUILTIN_ADV_PROTOCOLS: &str = include_str!("adv-protocols.json");
+
+lazy_static! {
+    static ref ADV_PROTOCOLS_PATH: PathBuf = {
+        std::env::current_exe().unwrap().parent().unwrap().join("adv-protocols.json")
+    };
+    static ref PROTOCOLS: Vec<AdvProtocol> = AdvProtocol::load().unwrap_or_else(|error| {
+        eprintln!("Failed to load {}, using the built-in ADV protocols: {}", ADV_PROTOCOLS_PATH.display(), error);
+        AdvProtocol::builtin()
+    });
+}
+
+/// Every known ADV protocol, loaded on first use
+pub fn protocols() -> &'static [AdvProtocol] {
+    &PROTOCOLS
+}
+
+/// Protocol with the given identifier, unknown identifiers fall back to the first one
+pub fn protocol(id: &str) -> &'static AdvProtocol {
+    PROTOCOLS.iter().find(|protocol| protocol.id == id).unwrap_or(&PROTOCOLS[0])
+}
+
+/// Turns an address and a command into the advertised bytes of a payload layout, and back
+pub trait AdvEncoder: Debug + Send + Sync {
+    /// Payload without the flags prefix
+    fn encode(&self, address: &[u8; 5], command: Command) -> Vec<u8>;
+    /// Fields of a payload without the flags prefix, `header_valid` tells whether it uses this layout
+    fn decode(&self, payload: &[u8]) -> anyhow::Result<DecodedPayload>;
+}
+
+/// Address and command whitened with two seeded LFSRs behind a fixed header, used by the classic remotes
+#[derive(Debug)]
+pub struct WhitenedEncoder {
+    pub scheme: WhiteningScheme,
+}
+
+impl AdvEncoder for WhitenedEncoder {
+    fn encode(&self, address: &[u8; 5], command: Command) -> Vec<u8> {
+        BleUtil::get_ble_command(&self.scheme, address, command)
+    }
+
+    fn decode(&self, payload: &[u8]) -> anyhow::Result<DecodedPayload> {
+        decode_whitened(&self.scheme, payload)
+    }
+}
+
+/// Header, address and command advertised as they are, for remotes without whitening or CRC
+#[derive(Debug)]
+pub struct PlainEncoder {
+    pub header: [u8; 3],
+}
+
+impl AdvEncoder for PlainEncoder {
+    fn encode(&self, address: &[u8; 5], command: Command) -> Vec<u8> {
+        let command = match command {
+            Command::Byte(byte) => [byte, 0, 0],
+            Command::Raw(bytes) => bytes,
+        };
+        [self.header.as_slice(), address, &command].concat()
+    }
+
+    fn decode(&self, payload: &[u8]) -> anyhow::Result<DecodedPayload> {
+        decode_plain(&self.header, payload)
+    }
+}
+
+/// Built-in program of a protocol, played instead of a speed level
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvPattern {
+    pub name: String,
+    command: Command,
+}
+
+/// Variant of the advertisement based toy protocol, speed levels and programs map to encoder commands
+#[derive(Debug)]
+pub struct AdvProtocol {
+    pub id: String,
+    pub name: String,
+    prefix: Vec<u8>, // AD flags in front of the encoded payload
+    encoder: Box<dyn AdvEncoder>,
+    speeds: Vec<Command>, // index is the level, 0 is off
+    pub patterns: Vec<AdvPattern>,
+}
+
+// Format of adv-protocols.json, commands and bytes are hex strings
+#[derive(Deserialize)]
+struct ProtocolsFile {
+    protocols: Vec<ProtocolEntry>,
+}
+
+#[derive(Deserialize)]
+struct ProtocolEntry {
+    id: String,
+    name: String,
+    #[serde(default = "ProtocolEntry::default_prefix")]
+    prefix: String,
+    encoder: EncoderEntry,
+    speeds: Vec<String>,
+    #[serde(default)]
+    patterns: Vec<PatternEntry>,
+    #[serde(default)]
+    test_vectors: Vec<TestVector>,
+}
+
+impl ProtocolEntry {
+    fn default_prefix() -> String {
+        "020106".into()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EncoderEntry {
+    Whitened { seeds: String, header: String },
+    Plain { header: String },
+}
+
+#[derive(Deserialize)]
+struct PatternEntry {
+    name: String,
+    command: String,
+}
+
+// Expected payload of a speed or a program, checked whenever the protocols are loaded
+#[derive(Deserialize)]
+struct TestVector {
+    address: String,
+    speed: Option<u8>,
+    pattern: Option<usize>,
+    payload: String,
+    #[serde(default)]
+    source: VectorSource,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum VectorSource {
+    Capture, // advertised by the stock remote
+    #[default]
+    Fixture, // produced by the encoder, only guards against regressions
+}
+
+impl AdvProtocol {
+    /// Protocols bundled with the application
+    pub fn builtin() -> Vec<Self> {
+        Self::parse(BUILTIN_ADV_PROTOCOLS).expect("Built-in ADV protocols are invalid")
+    }
+
+    /// Loads `adv-protocols.json` from next to the executable, or the built-in protocols if there is none
+    pub fn load() -> anyhow::Result<Vec<Self>> {
+        if !(*ADV_PROTOCOLS_PATH).exists() {
+            return Ok(Self::builtin());
+        }
+
+        let protocols = std::fs::read_to_string((*ADV_PROTOCOLS_PATH).clone())?;
+        Self::parse(&protocols)
+    }
+
+    /// Parses protocol definitions, a protocol without test vectors or with one it does not reproduce is an error
+    pub fn parse(json: &str) -> anyhow::Result<Vec<Self>> {
+        let file: ProtocolsFile = serde_json::from_str(json)?;
+        if file.protocols.is_empty() {
+            anyhow::bail!("No ADV protocols defined");
+        }
+
+        let mut protocols = Vec::new();
+        for entry in file.protocols {
+            let encoder: Box<dyn AdvEncoder> = match &entry.encoder {
+                EncoderEntry::Whitened { seeds, header } => Box::new(WhitenedEncoder {
+                    scheme: WhiteningScheme {
+                        seeds: parse_array(seeds)?,
+                        header: parse_array(header)?,
+                    },
+                }),
+                EncoderEntry::Plain { header } => Box::new(PlainEncoder {
+                    header: parse_array(header)?,
+                }),
+            };
+
+            let protocol = AdvProtocol {
+                prefix: parse_hex(&entry.prefix)?,
+                encoder,
+                speeds: entry.speeds.iter().map(|speed| parse_command(speed)).collect::<anyhow::Result<_>>()?,
+                patterns: entry.patterns
+                    .iter()
+                    .map(|pattern| Ok(AdvPattern { name: pattern.name.clone(), command: parse_command(&pattern.command)? }))
+                    .collect::<anyhow::Result<_>>()?,
+                id: entry.id,
+                name: entry.name,
+            };
+            if protocol.speeds.len() < 2 {
+                anyhow::bail!("Protocol {} needs an off command and at least one speed", protocol.id);
+            }
+            if entry.test_vectors.is_empty() {
+                anyhow::bail!("Protocol {} has no test vectors", protocol.id);
+            }
+            for vector in &entry.test_vectors {
+                protocol.check(vector)?;
+            }
+
+            protocols.push(protocol);
+        }
+
+        Ok(protocols)
+    }
+
+    fn check(&self, vector: &TestVector) -> anyhow::Result<()> {
+        let output = match (vector.speed, vector.pattern) {
+            (Some(speed), None) if (speed as usize) < self.speeds.len() => AdvOutput::Speed(speed),
+            (None, Some(pattern)) if pattern < self.patterns.len() => AdvOutput::Pattern(pattern),
+            _ => anyhow::bail!("Test vector {} of protocol {} needs a valid speed or pattern", vector.payload, self.id),
+        };
+
+        let payload = self.payload(&parse_array(&vector.address)?, output);
+        if payload != parse_hex(&vector.payload)? {
+            anyhow::bail!("{:?} test vector {:?} of protocol {} encodes to {}, expected {}", vector.source, output, self.id, hex::encode(payload), vector.payload);
+        }
+        Ok(())
+    }
+
+    /// Highest speed level
+    pub fn steps(&self) -> u8 {
+        (self.speeds.len() - 1) as u8
+    }
+
+    /// Full manufacturer data payload advertised for the output, unknown levels and programs turn the toy off
+    pub fn payload(&self, address: &[u8; 5], output: AdvOutput) -> Vec<u8> {
+        let command = match output {
+            AdvOutput::Speed(speed) => self.speeds.get(speed as usize),
+            AdvOutput::Pattern(pattern) => self.patterns.get(pattern).map(|pattern| &pattern.command),
+        };
+
+        let mut payload = self.prefix.clone();
+        payload.extend(self.encoder.encode(address, *command.unwrap_or(&self.speeds[0])));
+        payload
+    }
+
+    /// Decodes a payload with or without the flags prefix, the protocol and output are only set if the header matches
+    pub fn decode(&self, payload: &[u8]) -> anyhow::Result<DecodedPayload> {
+        let payload = payload.strip_prefix(self.prefix.as_slice()).unwrap_or(payload);
+        let mut decoded = self.encoder.decode(payload)?;
+        if decoded.header_valid() {
+            decoded.protocol = Some(self.id.clone());
+            decoded.output = (0..self.speeds.len())
+                .map(|speed| AdvOutput::Speed(speed as u8))
+                .chain((0..self.patterns.len()).map(AdvOutput::Pattern))
+                .find(|output| self.payload(&decoded.address, *output)[self.prefix.len()..] == *payload);
+        }
+        Ok(decoded)
+    }
+
+    /// Display name of an output, e.g. "speed 3" or the program name
+    pub fn output_name(&self, output: AdvOutput) -> String {
+        match output {
+            AdvOutput::Speed(speed) => format!("speed {}", speed),
+            AdvOutput::Pattern(pattern) => self.patterns
+                .get(pattern)
+                .map_or_else(|| format!("program {}", pattern + 1), |pattern| pattern.name.clone()),
+        }
+    }
+}
+
+/// One byte commands are encoded with their CRC, three byte commands replace it
+fn parse_command(text: &str) -> anyhow::Result<Command> {
+    match parse_hex(text)?.as_slice() {
+        [byte] => Ok(Command::Byte(*byte)),
+        [a, b, c] => Ok(Command::Raw([*a, *b, *c])),
+        _ => anyhow::bail!("Command {} is neither one nor three bytes", text),
+    }
+}
+
+fn parse_array<const N: usize>(text: &str) -> anyhow::Result<[u8; N]> {
+    parse_hex(text)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected {} bytes, got {}", N, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bluetooth::adv_decoder::decode;
+    use super::*;
+
+    const CUSTOM_PROTOCOL: &str = r#"{
+        "protocols": [{
+            "id": "custom",
+            "name": "Custom",
+            "prefix": "0201",
+            "encoder": { "type": "whitened", "seeds": "1a2b", "header": "aa55aa" },
+            "speeds": ["00", "01", "02", "03"],
+            "patterns": [{ "name": "Wave", "command": "123456" }],
+            "test_vectors": [
+                { "address": "0123456789", "speed": 2, "payload": "VECTOR", "source": "fixture" }
+            ]
+        }]
+    }"#;
+
+    const PLAIN_PROTOCOL: &str = r#"{
+        "protocols": [{
+            "id": "plain",
+            "name": "Plain",
+            "prefix": "0201",
+            "encoder": { "type": "plain", "header": "c0ffee" },
+            "speeds": ["000000", "a1b2c3", "10"],
+            "test_vectors": [
+                { "address": "0123456789", "speed": 1, "payload": "0201c0ffee0123456789a1b2c3", "source": "fixture" },
+                { "address": "0123456789", "speed": 2, "payload": "0201c0ffee0123456789100000", "source": "fixture" }
+            ]
+        }]
+    }"#;
+
+    #[test]
+    fn builtin_protocols_have_golden_vectors() {
+        let protocols = AdvProtocol::builtin();
+        assert_eq!(protocols[0].id, CLASSIC_PROTOCOL);
+        assert_eq!(protocols[0].steps(), 7);
+        assert_eq!(protocols[0].patterns.len(), 7);
+
+        // Encoder output alone would only test the encoder against itself
+        let file: ProtocolsFile = serde_json::from_str(BUILTIN_ADV_PROTOCOLS).unwrap();
+        for entry in &file.protocols {
+            assert!(entry.test_vectors.iter().any(|vector| vector.source == VectorSource::Capture), "{}", entry.id);
+        }
+
+        // Every protocol can be told apart by its header, and decodes its own programs
+        for protocol in &protocols {
+            let payload = protocol.payload(&[0x10, 0x20, 0x30, 0x40, 0x50], AdvOutput::Pattern(0));
+            let decoded = decode(&payload).unwrap();
+            assert_eq!(decoded.protocol.as_deref(), Some(protocol.id.as_str()));
+            assert_eq!(decoded.output, Some(AdvOutput::Pattern(0)));
+        }
+    }
+
+    #[test]
+    fn custom_protocols_round_trip() {
+        let protocols = AdvProtocol::parse(&CUSTOM_PROTOCOL.replace("VECTOR", "02010a2fa37e166549525507c4")).unwrap();
+        let custom = &protocols[0];
+        assert_eq!(custom.steps(), 3);
+
+        let address = [0x01, 0x23, 0x45, 0x67, 0x89];
+        let payload = custom.payload(&address, AdvOutput::Speed(3));
+        assert_ne!(payload[2..], protocol(CLASSIC_PROTOCOL).payload(&address, AdvOutput::Speed(3))[3..]);
+
+        let decoded = custom.decode(&payload).unwrap();
+        assert!(decoded.header_valid());
+        assert_eq!(decoded.address, address);
+        assert_eq!(decoded.byte_command, Some(0x03));
+        assert_eq!(decoded.output, Some(AdvOutput::Speed(3)));
+        assert_eq!(custom.decode(&custom.payload(&address, AdvOutput::Pattern(0))).unwrap().output, Some(AdvOutput::Pattern(0)));
+
+        // Payloads of other protocols do not match the header
+        let classic = protocol(CLASSIC_PROTOCOL).payload(&address, AdvOutput::Speed(3));
+        assert!(!custom.decode(&classic[3..]).unwrap().header_valid());
+    }
+
+    #[test]
+    fn plain_protocols_round_trip() {
+        let protocols = AdvProtocol::parse(PLAIN_PROTOCOL).unwrap();
+        let plain = &protocols[0];
+        let address = [0x01, 0x23, 0x45, 0x67, 0x89];
+
+        let decoded = plain.decode(&parse_hex("0201c0ffee0123456789a1b2c3").unwrap()).unwrap();
+        assert!(decoded.header_valid());
+        assert_eq!(decoded.address, address);
+        assert_eq!(decoded.raw_command, [0xa1, 0xb2, 0xc3]);
+        assert_eq!(decoded.byte_command, None);
+        assert_eq!(decoded.output, Some(AdvOutput::Speed(1)));
+
+        let decoded = plain.decode(&plain.payload(&address, AdvOutput::Speed(2))).unwrap();
+        assert_eq!(decoded.byte_command, Some(0x10));
+        assert_eq!(decoded.output, Some(AdvOutput::Speed(2)));
+
+        // Neither layout mistakes the other for its own
+        let classic = protocol(CLASSIC_PROTOCOL).payload(&address, AdvOutput::Speed(2));
+        assert!(!plain.decode(&classic[3..]).unwrap().header_valid());
+        assert!(!protocol(CLASSIC_PROTOCOL).decode(&plain.payload(&address, AdvOutput::Speed(2))[2..]).unwrap().header_valid());
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_vectors() {
+        let wrong = AdvProtocol::parse(&CUSTOM_PROTOCOL.replace("VECTOR", "0201000000000000000000000000")).unwrap_err();
+        assert!(wrong.to_string().contains("encodes to 02010a2fa37e166549525507c4"), "{}", wrong);
+
+        // The placeholder itself is a bad payload, unlike a missing vector
+        let placeholder = AdvProtocol::parse(CUSTOM_PROTOCOL).unwrap_err();
+        assert!(placeholder.to_string().contains("Invalid character"), "{}", placeholder);
+
+        let vector = r#"{ "address": "0123456789", "speed": 2, "payload": "VECTOR", "source": "fixture" }"#;
+        assert!(CUSTOM_PROTOCOL.contains(vector));
+        let missing = AdvProtocol::parse(&CUSTOM_PROTOCOL.replace(vector, "")).unwrap_err();
+        assert_eq!(missing.to_string(), "Protocol custom has no test vectors");
+
+        assert!(AdvProtocol::parse(&BUILTIN_ADV_PROTOCOLS.replace("ce60000", "ce70000")).is_err());
+    }
+}
@@ -2,6 +2,8 @@
 
 #[cfg(target_os = "windows")]
 pub mod ble_adv {
+    use std::collections::HashMap;
+    use std::sync::mpsc::Sender;
     use windows::{
         Devices::Bluetooth::Advertisement::{
             BluetoothLEAdvertisement, BluetoothLEAdvertisementPublisher,
@@ -10,39 +12,61 @@ pub mod ble_adv {
         Storage::Streams::DataWriter,
     };
     use windows::Foundation::TypedEventHandler;
-    use crate::bluetooth::generic::BleAdvertiser;
+    use crate::bluetooth::generic::{AdvOptions, BleAdvertiser};
 
     pub struct BleAdvertiserWindows {
-        publisher: Option<BluetoothLEAdvertisementPublisher>,
+        publishers: HashMap<String, BluetoothLEAdvertisementPublisher>, // by toy identifier
     }
 
     impl BleAdvertiserWindows {
         pub fn new() -> Self {
             Self {
-                publisher: None,
+                publishers: HashMap::new(),
             }
         }
     }
 
     impl BleAdvertiser for BleAdvertiserWindows {
-        async fn init(&mut self) -> anyhow::Result<()> {
+        async fn init(&mut self, _adapter: Option<&str>) -> anyhow::Result<()> {
             return Ok(());
-            // Stop and drop any existing publisher
-            if let Some(publisher) = self.publisher.take() {
+            // Stop and drop any existing publisher, they are created again by the next send
+            for publisher in self.publishers.values() {
                 let _ = publisher.Stop();
             }
+            self.publishers.clear();
+            Ok(())
+        }
+
+        async fn adapters(&self) -> anyhow::Result<Vec<String>> {
+            // The publisher always uses the system default adapter
+            Ok(Vec::new())
+        }
+
+        async fn stop(&mut self, identifier: &str) -> anyhow::Result<()> {
+            if let Some(publisher) = self.publishers.remove(identifier) {
+                publisher.Stop()
+                    .map_err(|e| anyhow::anyhow!("Failed to stop advertising: {}", e))?;
+            }
+            Ok(())
+        }
 
-            let publisher = BluetoothLEAdvertisementPublisher::new()
-                .map_err(|e| anyhow::anyhow!("Failed to create BLE publisher: {}", e))?;
+        async fn scan(&mut self, _company_id: u16, _scan_tx: Sender<(u16, Vec<u8>)>) -> anyhow::Result<()> {
+            anyhow::bail!("Learning from remotes is only supported on Linux")
+        }
 
-            self.publisher = Some(publisher);
+        async fn stop_scan(&mut self) -> anyhow::Result<()> {
             Ok(())
         }
 
-        async fn send(&mut self, _mfr_id: u16, _data: &[u8]) -> anyhow::Result<()> {
+        async fn send(&mut self, identifier: &str, _mfr_id: u16, _data: &[u8], _options: AdvOptions) -> anyhow::Result<()> {
             return Ok(());
-            let publisher = self.publisher.as_ref()
-                .ok_or_else(|| anyhow::anyhow!("Publisher not initialized. Call init() first."))?;
+            // The publisher does not expose the advertising interval, and the TX power is left to the system
+            if !self.publishers.contains_key(identifier) {
+                let publisher = BluetoothLEAdvertisementPublisher::new()
+                    .map_err(|e| anyhow::anyhow!("Failed to create BLE publisher: {}", e))?;
+                self.publishers.insert(identifier.into(), publisher);
+            }
+            let publisher = &self.publishers[identifier];
 
             let _ = publisher.Stop();
             let advertisement = publisher.Advertisement()?;
@@ -0,0 +1,183 @@
+const MAGIC: &[u8; 8] = b"btsnoop\0";
+const HEADER_LENGTH: usize = 16;
+const RECORD_HEADER_LENGTH: usize = 24;
+
+const DATALINK_HCI: u32 = 1001; // un-encapsulated, the packet type is in the record flags
+const DATALINK_H4: u32 = 1002; // UART, the packet type is the first byte
+
+const PACKET_COMMAND: u8 = 0x01;
+const PACKET_EVENT: u8 = 0x04;
+
+const LE_SET_ADVERTISING_DATA: u16 = 0x2008;
+const LE_SET_EXTENDED_ADVERTISING_DATA: u16 = 0x2037;
+const EVENT_LE_META: u8 = 0x3E;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0D;
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+
+/// Manufacturer data of every advertisement in a btsnoop HCI log, as company ID and data.
+///
+/// Both advertisements the capturing device sent (e.g. a phone running the stock remote app)
+/// and the ones it received from other devices are included.
+pub fn manufacturer_data(log: &[u8]) -> anyhow::Result<Vec<(u16, Vec<u8>)>> {
+    if log.len() < HEADER_LENGTH || &log[..8] != MAGIC {
+        anyhow::bail!("Not a btsnoop file");
+    }
+
+    let datalink = u32::from_be_bytes(log[12..16].try_into()?);
+    if datalink != DATALINK_HCI && datalink != DATALINK_H4 {
+        anyhow::bail!("Unsupported btsnoop datalink type {}", datalink);
+    }
+
+    let mut result = Vec::new();
+    let mut offset = HEADER_LENGTH;
+    while offset + RECORD_HEADER_LENGTH <= log.len() {
+        let record = &log[offset..offset + RECORD_HEADER_LENGTH];
+        let included_length = u32::from_be_bytes(record[4..8].try_into()?) as usize;
+        let flags = u32::from_be_bytes(record[8..12].try_into()?);
+        offset += RECORD_HEADER_LENGTH;
+
+        // Logs cut off while capturing end with a partial record
+        let Some(packet) = log.get(offset..offset + included_length) else {
+            break;
+        };
+        offset += included_length;
+
+        let (packet_type, packet) = match datalink {
+            DATALINK_H4 => match packet.split_first() {
+                Some((packet_type, packet)) => (*packet_type, packet),
+                None => continue,
+            },
+            _ if flags & 0b10 == 0 => continue, // ACL data
+            _ if flags & 0b01 == 0 => (PACKET_COMMAND, packet),
+            _ => (PACKET_EVENT, packet),
+        };
+
+        let advertising_data = match packet_type {
+            PACKET_COMMAND => command_advertising_data(packet),
+            PACKET_EVENT => event_advertising_data(packet),
+            _ => Vec::new(),
+        };
+        for data in advertising_data {
+            result.extend(ad_manufacturer_data(data));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Advertising data a device was told to send
+fn command_advertising_data(packet: &[u8]) -> Vec<&[u8]> {
+    let Some(params) = packet.get(3..) else {
+        return Vec::new();
+    };
+
+    let data = match u16::from_le_bytes([packet[0], packet[1]]) {
+        LE_SET_ADVERTISING_DATA => params.get(1..1 + *params.first().unwrap_or(&0) as usize),
+        LE_SET_EXTENDED_ADVERTISING_DATA => params.get(3).and_then(|length| params.get(4..4 + *length as usize)),
+        _ => None,
+    };
+
+    data.into_iter().collect()
+}
+
+/// Advertising data of the reports in an LE meta event
+fn event_advertising_data(packet: &[u8]) -> Vec<&[u8]> {
+    if packet.len() < 4 || packet[0] != EVENT_LE_META {
+        return Vec::new();
+    }
+
+    // Bytes of each report in front of its data length
+    let (subevent, reports) = (packet[2], &packet[3..]);
+    let data_offset = match subevent {
+        LE_ADVERTISING_REPORT => 8,
+        LE_EXTENDED_ADVERTISING_REPORT => 23,
+        _ => return Vec::new(),
+    };
+
+    let mut result = Vec::new();
+    let mut offset = 1;
+    for _ in 0..reports[0] {
+        let Some(&length) = reports.get(offset + data_offset) else {
+            break;
+        };
+        let start = offset + data_offset + 1;
+        let Some(data) = reports.get(start..start + length as usize) else {
+            break;
+        };
+        result.push(data);
+
+        // Legacy reports end with the RSSI
+        offset = start + length as usize + if subevent == LE_ADVERTISING_REPORT { 1 } else { 0 };
+    }
+
+    result
+}
+
+/// Manufacturer specific AD structures of advertising data
+fn ad_manufacturer_data(data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while let Some(&length) = data.get(offset) {
+        let Some(structure) = data.get(offset + 1..offset + 1 + length as usize) else {
+            break;
+        };
+        if length == 0 {
+            break;
+        }
+
+        if structure[0] == AD_MANUFACTURER_DATA && structure.len() >= 3 {
+            result.push((u16::from_le_bytes([structure[1], structure[2]]), structure[3..].to_vec()));
+        }
+        offset += 1 + length as usize;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(packet: &[u8], flags: u32) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend((packet.len() as u32).to_be_bytes());
+        record.extend((packet.len() as u32).to_be_bytes());
+        record.extend(flags.to_be_bytes());
+        record.extend(0u32.to_be_bytes());
+        record.extend(0i64.to_be_bytes());
+        record.extend(packet);
+        record
+    }
+
+    #[test]
+    fn reads_sent_and_received_advertisements() {
+        let advertising_data = [0x02, 0x01, 0x06, 0x05, 0xFF, 0xF0, 0xFF, 0xAB, 0xCD];
+
+        let mut log = MAGIC.to_vec();
+        log.extend(1u32.to_be_bytes());
+        log.extend(DATALINK_H4.to_be_bytes());
+
+        // LE Set Advertising Data command
+        let mut command = vec![PACKET_COMMAND, 0x08, 0x20, 32, advertising_data.len() as u8];
+        command.extend(advertising_data);
+        command.resize(4 + 32, 0);
+        log.extend(record(&command, 0));
+
+        // LE Advertising Report event
+        let mut report = vec![0x00, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, advertising_data.len() as u8];
+        report.extend(advertising_data);
+        report.push(0xC4);
+        let mut event = vec![PACKET_EVENT, EVENT_LE_META, (report.len() + 2) as u8, LE_ADVERTISING_REPORT, 1];
+        event.extend(report);
+        log.extend(record(&event, 1));
+
+        // Unrelated ACL data, and a record that was cut off
+        log.extend(record(&[0x02, 0x00, 0x20, 0x00], 0));
+        log.extend(&record(&command, 0)[..30]);
+
+        let expected = (0xFFF0, vec![0xAB, 0xCD]);
+        assert_eq!(manufacturer_data(&log).unwrap(), vec![expected.clone(), expected]);
+        assert!(manufacturer_data(b"not a log").is_err());
+    }
+}
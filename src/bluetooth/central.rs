@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use btleplug::api::{Central as _, CentralEvent, Characteristic, Peripheral as _, ScanFilter, Service, WriteType};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+/// Central operations of the GATT service, implemented by btleplug and by the fake used in tests
+pub trait BleCentral {
+    type Adapter: BleAdapter;
+
+    async fn adapters(&self) -> anyhow::Result<Vec<Self::Adapter>>;
+}
+
+pub trait BleAdapter: Clone + Send + Sync + 'static {
+    type Peripheral: BlePeripheral;
+
+    async fn name(&self) -> anyhow::Result<String>;
+    /// Starts an unfiltered scan, returns the advertisements and link losses seen by the adapter
+    async fn scan(&self) -> anyhow::Result<EventStream<AdapterEvent>>;
+    /// Discovered peripheral with the given address, `None` if the adapter has not seen it
+    fn peripheral(&self, address: &str) -> impl Future<Output = anyhow::Result<Option<Self::Peripheral>>> + Send;
+}
+
+/// Connecting and discovering services are `Send`, so inspections can run as their own task
+pub trait BlePeripheral: Clone + Send + Sync + 'static {
+    fn connect(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+    fn disconnect(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+    async fn is_connected(&self) -> anyhow::Result<bool>;
+    /// Discovers the GATT services of a connected peripheral, including their characteristics
+    fn discover_services(&self) -> impl Future<Output = anyhow::Result<Vec<Service>>> + Send;
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> anyhow::Result<()>;
+    /// Subscribes to a characteristic, returns the values it notifies
+    async fn subscribe(&self, characteristic: &Characteristic) -> anyhow::Result<EventStream<Vec<u8>>>;
+}
+
+#[derive(Debug, Clone)]
+pub enum AdapterEvent {
+    Advertisement(Advertisement),
+    Disconnected(String), // address
+}
+
+/// Properties a peripheral advertised, sent on discovery and on every update
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    pub address: String,
+    pub name: Option<String>,
+    pub services: Vec<Uuid>,
+    pub rssi: Option<i16>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    pub service_data: HashMap<Uuid, Vec<u8>>,
+}
+
+impl BleCentral for Manager {
+    type Adapter = Adapter;
+
+    async fn adapters(&self) -> anyhow::Result<Vec<Adapter>> {
+        Ok(btleplug::api::Manager::adapters(self).await?)
+    }
+}
+
+impl BleAdapter for Adapter {
+    type Peripheral = Peripheral;
+
+    async fn name(&self) -> anyhow::Result<String> {
+        Ok(self.adapter_info().await?)
+    }
+
+    async fn scan(&self) -> anyhow::Result<EventStream<AdapterEvent>> {
+        self.start_scan(ScanFilter::default()).await?;
+        let mut events = self.events().await?;
+
+        // Peripheral properties can only be read asynchronously, so the events are translated by a separate task
+        let (event_tx, event_rx) = unbounded_channel();
+        let adapter = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => {
+                        let Ok(peripheral) = btleplug::api::Central::peripheral(&adapter, &id).await else {
+                            continue;
+                        };
+                        let Ok(Some(props)) = peripheral.properties().await else {
+                            continue;
+                        };
+
+                        AdapterEvent::Advertisement(Advertisement {
+                            address: props.address.to_string(),
+                            name: props.local_name,
+                            services: props.services,
+                            rssi: props.rssi,
+                            manufacturer_data: props.manufacturer_data,
+                            service_data: props.service_data,
+                        })
+                    }
+                    CentralEvent::DeviceDisconnected(id) => match btleplug::api::Central::peripheral(&adapter, &id).await {
+                        Ok(peripheral) => AdapterEvent::Disconnected(peripheral.address().to_string()),
+                        Err(_) => continue,
+                    },
+                    _ => continue,
+                };
+
+                if event_tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Box::pin(UnboundedReceiverStream::new(event_rx)))
+    }
+
+    async fn peripheral(&self, address: &str) -> anyhow::Result<Option<Peripheral>> {
+        for peripheral in self.peripherals().await? {
+            if let Ok(Some(props)) = peripheral.properties().await && props.address.to_string() == address {
+                return Ok(Some(peripheral));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl BlePeripheral for Peripheral {
+    async fn connect(&self) -> anyhow::Result<()> {
+        Ok(btleplug::api::Peripheral::connect(self).await?)
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        Ok(btleplug::api::Peripheral::disconnect(self).await?)
+    }
+
+    async fn is_connected(&self) -> anyhow::Result<bool> {
+        Ok(btleplug::api::Peripheral::is_connected(self).await?)
+    }
+
+    async fn discover_services(&self) -> anyhow::Result<Vec<Service>> {
+        btleplug::api::Peripheral::discover_services(self).await?;
+        Ok(self.services().into_iter().collect())
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> anyhow::Result<()> {
+        Ok(btleplug::api::Peripheral::write(self, characteristic, data, write_type).await?)
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> anyhow::Result<EventStream<Vec<u8>>> {
+        btleplug::api::Peripheral::subscribe(self, characteristic).await?;
+
+        let uuid = characteristic.uuid;
+        let notifications = self.notifications().await?;
+        Ok(Box::pin(notifications.filter_map(move |notification| (notification.uuid == uuid).then_some(notification.value))))
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use uuid::Uuid;
+use wildmatch::WildMatch;
+use crate::bluetooth::protocol::ProtocolFamily;
+
+const BUILTIN_DEVICE_CONFIG: &str = include_str!("device-config.json");
+
+lazy_static! {
+    static ref DEVICE_CONFIG_PATH: PathBuf = {
+        std::env::current_exe().unwrap().parent().unwrap().join("device-config.json")
+    };
+}
+
+// Subset of the buttplug-device-config format, everything else in the file is ignored
+#[derive(Deserialize)]
+struct ConfigFile {
+    protocols: HashMap<String, ProtocolEntry>,
+}
+
+#[derive(Deserialize)]
+struct ProtocolEntry {
+    #[serde(default)]
+    communication: Vec<CommunicationEntry>,
+}
+
+#[derive(Deserialize)]
+struct CommunicationEntry {
+    btle: Option<BtleEntry>,
+}
+
+#[derive(Deserialize)]
+struct BtleEntry {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    services: HashMap<String, HashMap<String, String>>,
+}
+
+/// GATT protocol definitions of every supported toy
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    protocols: Vec<GattProtocol>,
+}
+
+impl DeviceConfig {
+    /// Definitions bundled with the application
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_DEVICE_CONFIG).expect("Built-in device config is invalid")
+    }
+
+    /// Loads `device-config.json` from next to the executable, or the built-in definitions if there is none
+    pub fn load() -> anyhow::Result<Self> {
+        if !(*DEVICE_CONFIG_PATH).exists() {
+            return Ok(Self::builtin());
+        }
+
+        let config = std::fs::read_to_string((*DEVICE_CONFIG_PATH).clone())?;
+        Self::parse(&config)
+    }
+
+    /// Parses a buttplug device config, protocols of unknown families are skipped
+    pub fn parse(json: &str) -> anyhow::Result<Self> {
+        let file: ConfigFile = serde_json::from_str(json)?;
+
+        let mut protocols = Vec::new();
+        for (identifier, entry) in file.protocols {
+            let Some(family) = ProtocolFamily::from_protocol_name(&identifier) else {
+                continue;
+            };
+
+            let mut names = Vec::new();
+            let mut services = Vec::new();
+            for btle in entry.communication.into_iter().filter_map(|communication| communication.btle) {
+                names.extend(btle.names.iter().map(|name| WildMatch::new(name)));
+                for (service, endpoints) in btle.services {
+                    services.push(GattEndpoints {
+                        service: Uuid::parse_str(&service)?,
+                        tx: endpoints.get("tx").map(|tx| Uuid::parse_str(tx)).transpose()?,
+                        rx: endpoints.get("rx").map(|rx| Uuid::parse_str(rx)).transpose()?,
+                    });
+                }
+            }
+
+            if names.is_empty() && services.is_empty() {
+                continue;
+            }
+
+            protocols.push(GattProtocol { identifier, family, names, services });
+        }
+
+        protocols.sort_by(|a, b| a.family.cmp(&b.family).then_with(|| a.identifier.cmp(&b.identifier)));
+        Ok(Self { protocols })
+    }
+
+    /// Finds the protocol of an advertising device, advertised services take precedence over names
+    pub fn find(&self, name: Option<&str>, services: &[Uuid]) -> Option<&GattProtocol> {
+        self.protocols
+            .iter()
+            .find(|protocol| protocol.endpoints(services).is_some())
+            .or_else(|| {
+                let name = name?;
+                self.protocols.iter().find(|protocol| protocol.names.iter().any(|pattern| pattern.matches(name)))
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GattProtocol {
+    /// Protocol name in the device config, e.g. `lovense`
+    pub identifier: String,
+    pub family: ProtocolFamily,
+    names: Vec<WildMatch>,
+    services: Vec<GattEndpoints>,
+}
+
+impl GattProtocol {
+    /// Endpoints of the first service of this protocol that the peripheral exposes. Services that only match
+    /// the protocol family have no known characteristics, those are found by their properties instead.
+    pub fn endpoints(&self, services: &[Uuid]) -> Option<GattEndpoints> {
+        self.services
+            .iter()
+            .find(|endpoints| services.contains(&endpoints.service))
+            .copied()
+            .or_else(|| {
+                let service = services.iter().find(|service| self.family.matches_service(service))?;
+                Some(GattEndpoints { service: *service, tx: None, rx: None })
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GattEndpoints {
+    pub service: Uuid,
+    pub tx: Option<Uuid>,
+    pub rx: Option<Uuid>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOVENSE_SERVICE: Uuid = Uuid::from_u128(0x455a0001_0023_4bd4_bbd5_a6920e4c5653);
+
+    #[test]
+    fn loads_builtin_config() {
+        let config = DeviceConfig::builtin();
+
+        let lovense = config.find(None, &[LOVENSE_SERVICE]).unwrap();
+        assert_eq!(lovense.family, ProtocolFamily::Lovense);
+        assert_eq!(lovense.endpoints(&[LOVENSE_SERVICE]).unwrap().tx, Some(Uuid::from_u128(0x455a0002_0023_4bd4_bbd5_a6920e4c5653)));
+
+        // Unlisted members of the Lovense service family
+        let unlisted_service = Uuid::from_u128(0x42300001_0025_4bd4_bbd5_a6920e4c5653);
+        let endpoints = config.find(None, &[unlisted_service]).unwrap().endpoints(&[unlisted_service]).unwrap();
+        assert_eq!(endpoints.service, unlisted_service);
+        assert_eq!(endpoints.tx, None);
+
+        assert_eq!(config.find(Some("LVS-Lush"), &[]).unwrap().family, ProtocolFamily::Lovense);
+        assert_eq!(config.find(Some("Sync"), &[]).unwrap().family, ProtocolFamily::WeVibe);
+        assert!(config.find(Some("Unknown"), &[]).is_none());
+    }
+
+    #[test]
+    fn skips_unknown_protocols() {
+        let config = DeviceConfig::parse(r#"{
+            "protocols": {
+                "lovense": { "communication": [{ "btle": { "names": ["LVS-*"], "services": {} } }] },
+                "unknown-toy": { "communication": [{ "btle": { "names": ["Toy"], "services": {} } }] }
+            }
+        }"#).unwrap();
+
+        assert!(config.find(Some("LVS-Edge"), &[]).is_some());
+        assert!(config.find(Some("Toy"), &[]).is_none());
+    }
+}
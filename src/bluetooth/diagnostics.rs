@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use btleplug::api::{CharPropFlags, Service};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use crate::bluetooth::central::Advertisement;
+
+lazy_static! {
+    static ref EXPORT_PATH: PathBuf = {
+        std::env::current_exe().unwrap().parent().unwrap().join("ble-diagnostics.json")
+    };
+}
+
+const PROPERTY_NAMES: &[(CharPropFlags, &str)] = &[
+    (CharPropFlags::BROADCAST, "broadcast"),
+    (CharPropFlags::READ, "read"),
+    (CharPropFlags::WRITE_WITHOUT_RESPONSE, "write-without-response"),
+    (CharPropFlags::WRITE, "write"),
+    (CharPropFlags::NOTIFY, "notify"),
+    (CharPropFlags::INDICATE, "indicate"),
+    (CharPropFlags::AUTHENTICATED_SIGNED_WRITES, "authenticated-signed-writes"),
+    (CharPropFlags::EXTENDED_PROPERTIES, "extended-properties"),
+];
+
+/// Every advertising device seen while diagnostics are enabled, exported as JSON for bug reports
+#[derive(Debug, Default, Serialize)]
+pub struct BleExplorer {
+    devices: BTreeMap<String, ExploredDevice>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExploredDevice {
+    pub address: String,
+    pub name: Option<String>,
+    pub adapter: String,
+    pub rssi: Option<i16>,
+    pub services: Vec<String>,
+    pub manufacturer_data: BTreeMap<String, String>, // company ID, hex encoded data
+    pub service_data: BTreeMap<String, String>, // service UUID, hex encoded data
+    pub gatt: Option<GattInspection>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum GattInspection {
+    Pending,
+    Services(Vec<ServiceInfo>),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceInfo {
+    pub uuid: String,
+    pub primary: bool,
+    pub characteristics: Vec<CharacteristicInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CharacteristicInfo {
+    pub uuid: String,
+    pub properties: Vec<&'static str>,
+}
+
+impl BleExplorer {
+    /// Adds a device or updates it with its latest advertisement, GATT results are kept
+    pub fn advertisement(&mut self, adapter: &str, advertisement: Advertisement) {
+        let previous = self.devices.remove(&advertisement.address);
+        let device = ExploredDevice {
+            address: advertisement.address.clone(),
+            name: advertisement.name,
+            adapter: adapter.into(),
+            rssi: advertisement.rssi.or(previous.as_ref().and_then(|device| device.rssi)),
+            services: advertisement.services.iter().map(|service| service.to_string()).collect(),
+            manufacturer_data: advertisement.manufacturer_data
+                .iter()
+                .map(|(company_id, data)| (format!("0x{:04X}", company_id), hex::encode(data)))
+                .collect(),
+            service_data: advertisement.service_data
+                .iter()
+                .map(|(service, data)| (service.to_string(), hex::encode(data)))
+                .collect(),
+            gatt: previous.and_then(|device| device.gatt),
+        };
+
+        self.devices.insert(advertisement.address, device);
+    }
+
+    pub fn set_gatt(&mut self, address: &str, gatt: GattInspection) {
+        if let Some(device) = self.devices.get_mut(address) {
+            device.gatt.replace(gatt);
+        }
+    }
+
+    /// Devices ordered by signal strength, strongest first
+    pub fn devices(&self) -> Vec<&ExploredDevice> {
+        let mut devices = self.devices.values().collect::<Vec<&ExploredDevice>>();
+        devices.sort_by_key(|device| std::cmp::Reverse(device.rssi.unwrap_or(i16::MIN)));
+        devices
+    }
+
+    pub fn clear(&mut self) {
+        self.devices.clear();
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Writes `ble-diagnostics.json` next to the executable, returns its path
+    pub fn export(&self) -> anyhow::Result<PathBuf> {
+        std::fs::write((*EXPORT_PATH).clone(), self.to_json()?)?;
+        Ok((*EXPORT_PATH).clone())
+    }
+}
+
+impl GattInspection {
+    pub fn from_services(services: Result<Vec<Service>, String>) -> Self {
+        match services {
+            Ok(services) => GattInspection::Services(services.into_iter().map(ServiceInfo::from).collect()),
+            Err(error) => GattInspection::Failed(error),
+        }
+    }
+}
+
+impl From<Service> for ServiceInfo {
+    fn from(service: Service) -> Self {
+        Self {
+            uuid: service.uuid.to_string(),
+            primary: service.primary,
+            characteristics: service.characteristics
+                .into_iter()
+                .map(|characteristic| CharacteristicInfo {
+                    uuid: characteristic.uuid.to_string(),
+                    properties: PROPERTY_NAMES
+                        .iter()
+                        .filter(|(flag, _)| characteristic.properties.contains(*flag))
+                        .map(|(_, name)| *name)
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use btleplug::api::Characteristic;
+    use uuid::Uuid;
+    use super::*;
+
+    #[test]
+    fn exports_devices_as_json() {
+        let mut explorer = BleExplorer::default();
+        let advertisement = Advertisement {
+            address: "AA:BB:CC:DD:EE:FF".into(),
+            name: Some("Toy".into()),
+            services: vec![Uuid::from_u128(0x1900)],
+            rssi: Some(-70),
+            manufacturer_data: HashMap::from([(0xFFF0, vec![0x6d, 0xb6])]),
+            service_data: HashMap::new(),
+        };
+        explorer.advertisement("hci0", advertisement.clone());
+
+        let service = Uuid::from_u128(0x1900);
+        explorer.set_gatt("AA:BB:CC:DD:EE:FF", GattInspection::from_services(Ok(vec![Service {
+            uuid: service,
+            primary: true,
+            characteristics: BTreeSet::from([Characteristic {
+                uuid: Uuid::from_u128(0x1902),
+                service_uuid: service,
+                properties: CharPropFlags::WRITE | CharPropFlags::NOTIFY,
+                descriptors: BTreeSet::new(),
+            }]),
+        }])));
+
+        // A new advertisement keeps the inspected services
+        explorer.advertisement("hci0", Advertisement { rssi: Some(-50), ..advertisement });
+
+        let json: serde_json::Value = serde_json::from_str(&explorer.to_json().unwrap()).unwrap();
+        let device = &json["devices"]["AA:BB:CC:DD:EE:FF"];
+        assert_eq!(device["rssi"], -50);
+        assert_eq!(device["manufacturer_data"]["0xFFF0"], "6db6");
+        assert_eq!(device["gatt"]["Services"][0]["characteristics"][0]["properties"], serde_json::json!(["write", "notify"]));
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use btleplug::api::{CharPropFlags, Characteristic, Service, WriteType};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use uuid::Uuid;
+use crate::bluetooth::central::{AdapterEvent, Advertisement, BleAdapter, BleCentral, BlePeripheral, EventStream};
+use crate::bluetooth::lovense::LovenseCommand;
+
+const LOVENSE_SERVICE: Uuid = Uuid::from_u128(0x455a0001_0023_4bd4_bbd5_a6920e4c5653);
+const LOVENSE_TX: Uuid = Uuid::from_u128(0x455a0002_0023_4bd4_bbd5_a6920e4c5653);
+const LOVENSE_RX: Uuid = Uuid::from_u128(0x455a0003_0023_4bd4_bbd5_a6920e4c5653);
+
+/// In-memory central without a radio, its adapters and peripherals are driven by the test
+pub struct FakeCentral {
+    adapters: Vec<FakeAdapter>,
+}
+
+impl FakeCentral {
+    pub fn new(adapters: Vec<FakeAdapter>) -> Self {
+        Self { adapters }
+    }
+}
+
+impl BleCentral for FakeCentral {
+    type Adapter = FakeAdapter;
+
+    async fn adapters(&self) -> anyhow::Result<Vec<FakeAdapter>> {
+        Ok(self.adapters.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct FakeAdapter {
+    name: String,
+    state: Arc<Mutex<AdapterState>>,
+}
+
+struct AdapterState {
+    peripherals: Vec<FakePeripheral>,
+    event_tx: UnboundedSender<AdapterEvent>,
+    event_rx: Option<UnboundedReceiver<AdapterEvent>>,
+}
+
+impl FakeAdapter {
+    pub fn new(name: &str) -> Self {
+        let (event_tx, event_rx) = unbounded_channel();
+        Self {
+            name: name.into(),
+            state: Arc::new(Mutex::new(AdapterState {
+                peripherals: Vec::new(),
+                event_tx,
+                event_rx: Some(event_rx),
+            })),
+        }
+    }
+
+    /// Makes the next scan fail
+    pub fn fail_scan(&self) {
+        self.state.lock().expect("Could not lock").event_rx.take();
+    }
+
+    /// Makes a peripheral visible to the adapter, events are queued until the scan starts
+    pub fn advertise(&self, peripheral: &FakePeripheral) {
+        let mut state = self.state.lock().expect("Could not lock");
+        if !state.peripherals.iter().any(|known| Arc::ptr_eq(&known.state, &peripheral.state)) {
+            state.peripherals.push(peripheral.clone());
+        }
+
+        let mut peripheral_state = peripheral.state.lock().expect("Could not lock");
+        peripheral_state.event_txs.push(state.event_tx.clone());
+        _ = state.event_tx.send(AdapterEvent::Advertisement(peripheral_state.advertisement.clone()));
+    }
+}
+
+impl BleAdapter for FakeAdapter {
+    type Peripheral = FakePeripheral;
+
+    async fn name(&self) -> anyhow::Result<String> {
+        Ok(self.name.clone())
+    }
+
+    async fn scan(&self) -> anyhow::Result<EventStream<AdapterEvent>> {
+        let Some(event_rx) = self.state.lock().expect("Could not lock").event_rx.take() else {
+            anyhow::bail!("Already scanning");
+        };
+
+        Ok(Box::pin(UnboundedReceiverStream::new(event_rx)))
+    }
+
+    async fn peripheral(&self, address: &str) -> anyhow::Result<Option<FakePeripheral>> {
+        let state = self.state.lock().expect("Could not lock");
+        Ok(state.peripherals.iter().find(|peripheral| peripheral.address() == address).cloned())
+    }
+}
+
+/// Simulated toy that records every write
+#[derive(Clone)]
+pub struct FakePeripheral {
+    state: Arc<Mutex<PeripheralState>>,
+}
+
+struct PeripheralState {
+    advertisement: Advertisement,
+    services: Vec<Service>,
+    connected: bool,
+    failing_connects: u32,
+    connect_gate: Option<UnboundedReceiver<()>>, // the next connection attempt waits for a release
+    failing_writes: bool,
+    answers_commands: bool,
+    written: Vec<Vec<u8>>,
+    notification_tx: Option<UnboundedSender<Vec<u8>>>,
+    event_txs: Vec<UnboundedSender<AdapterEvent>>,
+}
+
+impl FakePeripheral {
+    /// Lovense Lush that answers commands the way the real toy does
+    pub fn lovense(address: &str) -> Self {
+        let characteristic = |uuid, properties| Characteristic {
+            uuid,
+            service_uuid: LOVENSE_SERVICE,
+            properties,
+            descriptors: BTreeSet::new(),
+        };
+
+        Self {
+            state: Arc::new(Mutex::new(PeripheralState {
+                advertisement: Advertisement {
+                    address: address.into(),
+                    name: Some("LVS-Lush".into()),
+                    services: vec![LOVENSE_SERVICE],
+                    rssi: Some(-60),
+                    manufacturer_data: HashMap::new(),
+                    service_data: HashMap::new(),
+                },
+                services: vec![Service {
+                    uuid: LOVENSE_SERVICE,
+                    primary: true,
+                    characteristics: BTreeSet::from([
+                        characteristic(LOVENSE_TX, CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE),
+                        characteristic(LOVENSE_RX, CharPropFlags::NOTIFY),
+                    ]),
+                }],
+                connected: false,
+                failing_connects: 0,
+                connect_gate: None,
+                failing_writes: false,
+                answers_commands: true,
+                written: Vec::new(),
+                notification_tx: None,
+                event_txs: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn address(&self) -> String {
+        self.state.lock().expect("Could not lock").advertisement.address.clone()
+    }
+
+    /// Refuses the next connection attempts
+    pub fn fail_connects(&self, count: u32) {
+        self.state.lock().expect("Could not lock").failing_connects = count;
+    }
+
+    /// Makes the next connection attempt hang like an unresponsive device, until the returned sender releases it
+    pub fn hold_connect(&self) -> UnboundedSender<()> {
+        let (release_tx, release_rx) = unbounded_channel();
+        self.state.lock().expect("Could not lock").connect_gate.replace(release_rx);
+        release_tx
+    }
+
+    pub fn fail_writes(&self, failing: bool) {
+        self.state.lock().expect("Could not lock").failing_writes = failing;
+    }
+
+    /// Keeps accepting writes without answering them
+    pub fn mute(&self) {
+        self.state.lock().expect("Could not lock").answers_commands = false;
+    }
+
+    /// Drops the connection as if the toy went out of range
+    pub fn drop_link(&self) {
+        let mut state = self.state.lock().expect("Could not lock");
+        state.connected = false;
+        state.notification_tx.take();
+        for event_tx in &state.event_txs {
+            _ = event_tx.send(AdapterEvent::Disconnected(state.advertisement.address.clone()));
+        }
+    }
+
+    /// Payloads written since the last call
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.state.lock().expect("Could not lock").written)
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.state.lock().expect("Could not lock").connected
+    }
+}
+
+impl BlePeripheral for FakePeripheral {
+    async fn connect(&self) -> anyhow::Result<()> {
+        let gate = self.state.lock().expect("Could not lock").connect_gate.take();
+        if let Some(mut gate) = gate {
+            gate.recv().await;
+        }
+
+        let mut state = self.state.lock().expect("Could not lock");
+        if state.failing_connects > 0 {
+            state.failing_connects -= 1;
+            anyhow::bail!("Connection refused");
+        }
+
+        state.connected = true;
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Could not lock");
+        state.connected = false;
+        state.notification_tx.take();
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> anyhow::Result<bool> {
+        Ok(self.is_linked())
+    }
+
+    async fn discover_services(&self) -> anyhow::Result<Vec<Service>> {
+        let state = self.state.lock().expect("Could not lock");
+        if !state.connected {
+            anyhow::bail!("Not connected");
+        }
+
+        Ok(state.services.clone())
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], _write_type: WriteType) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("Could not lock");
+        if !state.connected {
+            anyhow::bail!("Not connected");
+        }
+        if state.failing_writes {
+            anyhow::bail!("Write rejected");
+        }
+        if characteristic.uuid != LOVENSE_TX {
+            anyhow::bail!("Characteristic is not writable");
+        }
+
+        state.written.push(data.to_vec());
+
+        let reply = match LovenseCommand::parse(data) {
+            Some(LovenseCommand::Battery) => "85;",
+            Some(LovenseCommand::DeviceType) => "S:11:0082059AD3BD;",
+            Some(_) => "OK;",
+            None => "ERR;",
+        };
+        if state.answers_commands && let Some(notification_tx) = &state.notification_tx {
+            _ = notification_tx.send(reply.as_bytes().to_vec());
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> anyhow::Result<EventStream<Vec<u8>>> {
+        if characteristic.uuid != LOVENSE_RX {
+            anyhow::bail!("Characteristic does not notify");
+        }
+
+        let (notification_tx, notification_rx) = unbounded_channel();
+        self.state.lock().expect("Could not lock").notification_tx.replace(notification_tx);
+        Ok(Box::pin(UnboundedReceiverStream::new(notification_rx)))
+    }
+}
@@ -1,10 +1,17 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use crate::consts::{LOVENSE_SERVICE_UUID, LOVENSE_TX_UUID};
-use btleplug::api::{Central as _, CentralEvent, Manager as _, Peripheral as _, ScanFilter, WriteType};
-use btleplug::platform::{Manager, Peripheral};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use crate::bluetooth::central::{AdapterEvent, Advertisement, BleAdapter, BleCentral, BlePeripheral};
+use crate::bluetooth::device_config::{DeviceConfig, GattProtocol};
+use crate::bluetooth::lovense::{LovenseCommand, LovenseDeviceType, LovenseReply};
+use crate::bluetooth::protocol::ProtocolFamily;
+use crate::device::{intensity_to_level, OutputDevice, SharedWriteStats, WriteStats, WriteTiming};
+use btleplug::api::{CharPropFlags, Characteristic, Service, WriteType};
+use btleplug::platform::Manager;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
@@ -32,11 +39,18 @@ macro_rules! some_check {
     };
 }
 
+const COMMAND_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+const BATTERY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const MAX_UNANSWERED_COMMANDS: u32 = 5;
+
 pub struct BluetoothGattService {
     ble_rx: Option<Receiver<BleMessage>>,
     ble_tx: Option<Sender<BleCommand>>,
 
-    last_speed: u8,
+    device_config: DeviceConfig,
     thread_running: Arc<AtomicBool>,
 }
 
@@ -45,7 +59,10 @@ impl BluetoothGattService {
         let mut result = Self {
             ble_rx: None,
             ble_tx: None,
-            last_speed: 0,
+            device_config: DeviceConfig::load().unwrap_or_else(|error| {
+                eprintln!("Failed to load device config, using built-in definitions: {}", error);
+                DeviceConfig::builtin()
+            }),
             thread_running: Arc::new(AtomicBool::new(false)),
         };
 
@@ -66,9 +83,10 @@ impl BluetoothGattService {
         self.ble_rx.replace(ble_rx);
 
         let thread_running = self.thread_running.clone();
+        let device_config = self.device_config.clone();
         thread::spawn(move || {
             thread_running.store(true, Ordering::Relaxed);
-            Self::ble_thread(gui_tx, gui_rx);
+            Self::ble_thread(gui_tx, gui_rx, device_config);
             thread_running.store(false, Ordering::Relaxed);
         });
     }
@@ -81,152 +99,447 @@ impl BluetoothGattService {
         None
     }
 
-    pub fn connect(&mut self, device: &BluetoothGattDevice) -> anyhow::Result<()> {
+    /// Forwards every advertisement to the GUI, not only the ones of supported toys
+    pub fn set_diagnostics(&self, enabled: bool) {
         if let Some(ble_tx) = &self.ble_tx {
-            ble_tx.send(BleCommand::Connect(device.device_address.clone()))?;
-            return Ok(());
+            _ = ble_tx.send(BleCommand::SetDiagnostics(enabled));
         }
-
-        Err(anyhow::anyhow!("Missing message channels!"))
     }
 
-    pub fn disconnect(&mut self) -> anyhow::Result<()> {
+    /// Lists the GATT services of any device, connecting to it temporarily if needed
+    pub fn inspect(&self, address: &str, adapter: Option<String>) {
         if let Some(ble_tx) = &self.ble_tx {
-            ble_tx.send(BleCommand::Disconnect)?;
-            return Ok(());
+            _ = ble_tx.send(BleCommand::Inspect(address.into(), adapter));
         }
-
-        Err(anyhow::anyhow!("Missing message channels!"))
     }
 
-    pub fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+    pub fn create_device(&self, device: BluetoothGattDevice) -> anyhow::Result<GattOutputDevice> {
         if let Some(ble_tx) = &self.ble_tx {
-            ble_tx.send(BleCommand::SendData(data.to_vec()))?;
-            return Ok(());
+            return Ok(GattOutputDevice {
+                device,
+                ble_tx: ble_tx.clone(),
+                last_level: None,
+                last_motor_levels: HashMap::new(),
+                adapter: None,
+                write_timing: WriteTiming::default(),
+                write_stats: SharedWriteStats::default(),
+            });
         }
 
         Err(anyhow::anyhow!("Missing message channels!"))
     }
 
-    pub fn send_speed(&mut self, speed: u8) -> anyhow::Result<()> {
-        if speed == self.last_speed {
-            return Ok(());
-        }
-
-        self.last_speed = speed;
-
-        self.send_data(format!("Vibrate:{};", speed.clamp(0, 20)).as_bytes())
-    }
-
-    fn ble_thread(gui_tx: Sender<BleMessage>, gui_rx: Receiver<BleCommand>) {
+    fn ble_thread(gui_tx: Sender<BleMessage>, gui_rx: Receiver<BleCommand>, device_config: DeviceConfig) {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
             let manager = error_check!(Manager::new().await, gui_tx, "Failed to create BLE manager");
-            let adapters = error_check!(manager.adapters().await, gui_tx, "Failed to get adapters");
-            let adapter = some_check!(adapters.into_iter().next(), gui_tx, "No adapters found");
+            Self::run(manager, gui_tx, gui_rx, device_config).await;
+        });
+    }
 
-            error_check!(adapter.start_scan(ScanFilter {
-                services: vec![Uuid::parse_str(LOVENSE_SERVICE_UUID).unwrap()]
-            }).await, gui_tx, "Failed to start scan");
+    /// Scans, connects and writes through the given central until the GUI closes its command channel
+    async fn run<C: BleCentral>(central: C, gui_tx: Sender<BleMessage>, gui_rx: Receiver<BleCommand>, device_config: DeviceConfig) {
+        let adapters = error_check!(central.adapters().await, gui_tx, "Failed to get adapters");
+        some_check!(adapters.first(), gui_tx, "No adapters found");
 
-            let _ = gui_tx.send(BleMessage::AdapterInitialized);
+        let tx_clone_2 = gui_tx.clone();
+        let (link_tx, link_rx) = channel::<String>();
+        let diagnostics = Arc::new(AtomicBool::new(false));
 
-            let mut events = error_check!(adapter.events().await, gui_tx, "Failed to get BLE events");
+        // Every adapter scans, devices are connected through the adapter selected for them
+        let mut named_adapters: Vec<(String, C::Adapter)> = Vec::new();
+        for (index, adapter) in adapters.into_iter().enumerate() {
+            // A broken adapter must not take the others down with it
+            let adapter_name = match adapter.name().await {
+                Ok(adapter_name) => adapter_name,
+                Err(error) => {
+                    eprintln!("Failed to get info of adapter {}: {}", index, error);
+                    _ = gui_tx.send(BleMessage::AdapterFailed(format!("Adapter {}", index), format!("Failed to get adapter info: {}", error)));
+                    continue;
+                }
+            };
+
+            // Unfiltered, some toys are only recognizable by their advertised name
+            let mut events = match adapter.scan().await {
+                Ok(events) => events,
+                Err(error) => {
+                    eprintln!("Failed to start scan on {}: {}", adapter_name, error);
+                    _ = gui_tx.send(BleMessage::AdapterFailed(adapter_name, format!("Failed to start scan: {}", error)));
+                    continue;
+                }
+            };
 
             let tx_clone = gui_tx.clone();
-            let tx_clone_2 = gui_tx.clone();
-            let adapter_clone = adapter.clone();
+            let link_tx = link_tx.clone();
+            let device_config = device_config.clone();
+            let diagnostics = diagnostics.clone();
+            let adapter_clone = adapter_name.clone();
 
             tokio::spawn(async move {
                 while let Some(event) = events.next().await {
                     match event {
-                        CentralEvent::DeviceDiscovered(id) => {
-                            if let Ok(peripheral) = adapter_clone.peripheral(&id).await {
-                                if let Ok(Some(props)) = peripheral.properties().await {
-                                    // println!("{}: {}", "Props from update".green().bold(), format!("{:?}", props).white());
-
-                                    let mut is_valid = false;
-                                    for service in props.services {
-                                        if service.to_string() == "455a0001-0023-4bd4-bbd5-a6920e4c5653" {
-                                            is_valid = true;
-                                        }
-                                    }
-                                    if !is_valid {
-                                        continue;
-                                    }
-
-                                    let _ = tx_clone.send(BleMessage::DeviceDiscovered(BluetoothGattDevice {
-                                        device_address: props.address.to_string(),
-                                        device_name: props.local_name.clone(),
-                                    }));
-                                }
+                        // Updates carry the current signal strength and keep the device present in the GUI
+                        AdapterEvent::Advertisement(advertisement) => {
+                            if diagnostics.load(Ordering::Relaxed) {
+                                _ = tx_clone.send(BleMessage::Advertisement(adapter_clone.clone(), advertisement.clone()));
                             }
+
+                            let Some(protocol) = device_config.find(advertisement.name.as_deref(), &advertisement.services) else {
+                                continue;
+                            };
+
+                            let _ = tx_clone.send(BleMessage::DeviceDiscovered(BluetoothGattDevice {
+                                device_address: advertisement.address,
+                                device_name: advertisement.name,
+                                rssi: advertisement.rssi,
+                                protocol: protocol.clone(),
+                            }));
                         }
-                        /*CentralEvent::DeviceUpdated(id) => {
-                            if let Ok(peripheral) = adapter_clone.peripheral(&id).await {
-                                if let Ok(Some(props)) = peripheral.properties().await {
-                                    // println!("{}: {}", "Props from update".cyan(), format!("{:?}", props).white());
-                                }
-                            }
-                        }*/
-                        _ => continue,
+                        AdapterEvent::Disconnected(address) => _ = link_tx.send(address),
                     }
                 }
             });
 
-            let mut connected_peripheral: Option<Peripheral> = None;
-
-            loop {
-                if let Ok(command) = gui_rx.recv() {
-                    match command {
-                        BleCommand::Connect(address) => {
-                            if let Ok(peripherals) = adapter.peripherals().await {
-                                for peripheral in peripherals {
-                                    if let Ok(Some(props)) = peripheral.properties().await {
-                                        if props.address.to_string() == address {
-                                            println!("Connecting...");
-                                            _ = tx_clone_2.send(BleMessage::DeviceConnecting(address.clone()));
-                                            if let Err(_error) = peripheral.connect().await {
-                                                eprintln!("Failed to connect peripheral: {}", _error);
-                                            } else {
-                                                _ = peripheral.discover_services().await;
-                                                connected_peripheral.replace(peripheral);
-                                                println!("Connected to {}", address);
-                                                _ = tx_clone_2.send(BleMessage::DeviceConnected(address.clone()));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+            named_adapters.push((adapter_name, adapter));
+        }
+        some_check!(named_adapters.first(), gui_tx, "No usable adapters found");
+
+        let _ = gui_tx.send(BleMessage::AdapterInitialized);
+        let _ = gui_tx.send(BleMessage::AdaptersFound(named_adapters.iter().map(|(name, _)| name.clone()).collect()));
+
+        let mut connected_peripherals: HashMap<String, ConnectedPeripheral<<C::Adapter as BleAdapter>::Peripheral>> = HashMap::new();
+        let mut reconnecting: HashMap<String, PendingReconnect> = HashMap::new();
+        let mut pending_writes: HashMap<(String, u8), PendingWrite> = HashMap::new(); // (address, motor)
+        let mut last_battery_poll = Instant::now();
+        let mut last_link_check = Instant::now();
+
+        loop {
+            // Wake up early when a pending intensity write or a keepalive becomes due
+            let write_delay = pending_writes.iter()
+                .map(|((address, _), write)| connected_peripherals.get(address).map_or(Duration::ZERO, |connected| connected.write_delay(write.timing.min_interval)))
+                .chain(connected_peripherals.values().filter_map(|connected| connected.keepalive_delay()))
+                .min();
+            let timeout = write_delay.map_or(COMMAND_POLL_TIMEOUT, |delay| delay.min(COMMAND_POLL_TIMEOUT));
+
+            let mut commands = match gui_rx.recv_timeout(timeout) {
+                Ok(command) => vec![command],
+                Err(RecvTimeoutError::Timeout) => Vec::new(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            commands.extend(gui_rx.try_iter());
+
+            // Disconnect events may be missed on some platforms, so the links are also checked periodically
+            let mut lost_addresses = link_rx.try_iter().collect::<Vec<String>>();
+            if last_link_check.elapsed() >= LINK_CHECK_INTERVAL {
+                last_link_check = Instant::now();
+                for (address, connected) in &connected_peripherals {
+                    if !connected.peripheral.is_connected().await.unwrap_or(false) {
+                        lost_addresses.push(address.clone());
+                    }
+                }
+            }
+
+            for address in lost_addresses {
+                if let Some(connected) = connected_peripherals.remove(&address) {
+                    eprintln!("Lost connection to {}", address);
+                    _ = tx_clone_2.send(BleMessage::DeviceDisconnected(address.clone()));
+                    reconnecting.insert(address, PendingReconnect {
+                        protocol: connected.protocol.clone(),
+                        adapter: connected.adapter.clone(),
+                        attempt: 0,
+                        next_attempt: Instant::now(),
+                    });
+                }
+            }
+
+            let due_addresses = reconnecting.iter()
+                .filter(|(_, pending)| pending.next_attempt <= Instant::now())
+                .map(|(address, _)| address.clone())
+                .collect::<Vec<String>>();
+            for address in due_addresses {
+                let Some(pending) = reconnecting.get_mut(&address) else {
+                    continue;
+                };
+
+                pending.attempt += 1;
+                _ = tx_clone_2.send(BleMessage::Reconnecting(address.clone(), pending.attempt));
+                match Self::connect_peripheral(&named_adapters, pending.adapter.as_deref(), &address, &pending.protocol, &tx_clone_2).await {
+                    Ok(connected) => {
+                        reconnecting.remove(&address);
+                        connected_peripherals.insert(address.clone(), connected);
+                        _ = tx_clone_2.send(BleMessage::DeviceConnected(address));
+                    }
+                    Err(error) => {
+                        eprintln!("Failed to reconnect {}: {}", address, error);
+                        pending.next_attempt = Instant::now() + Self::reconnect_delay(pending.attempt);
+                    }
+                }
+            }
+
+            if last_battery_poll.elapsed() >= BATTERY_POLL_INTERVAL {
+                last_battery_poll = Instant::now();
+                for connected in connected_peripherals.values().filter(|connected| connected.protocol.family == ProtocolFamily::Lovense) {
+                    Self::write_data(connected, &LovenseCommand::Battery.encode()).await;
+                }
+            }
+
+            for command in commands {
+                match command {
+                    BleCommand::Connect(address, protocol, adapter_name) => {
+                        if connected_peripherals.contains_key(&address) {
+                            _ = tx_clone_2.send(BleMessage::DeviceConnected(address.clone()));
+                            continue;
                         }
-                        BleCommand::Disconnect => {
-                            if let Some(peripheral) = connected_peripheral.take() {
-                                let _ = peripheral.disconnect().await;
-                                _ = tx_clone_2.send(BleMessage::DeviceDisconnected(peripheral.address().to_string()));
+
+                        reconnecting.remove(&address);
+                        _ = tx_clone_2.send(BleMessage::DeviceConnecting(address.clone()));
+                        match Self::connect_peripheral(&named_adapters, adapter_name.as_deref(), &address, &protocol, &tx_clone_2).await {
+                            Ok(connected) => {
+                                connected_peripherals.insert(address.clone(), connected);
+                                _ = tx_clone_2.send(BleMessage::DeviceConnected(address.clone()));
                             }
+                            Err(error) => {
+                                eprintln!("Failed to connect {}: {}", address, error);
+                                _ = tx_clone_2.send(BleMessage::ConnectFailed(address.clone(), error.to_string()));
+                                _ = tx_clone_2.send(BleMessage::DeviceDisconnected(address.clone()));
+                            }
+                        }
+                    }
+                    BleCommand::Disconnect(address) => {
+                        let was_reconnecting = reconnecting.remove(&address).is_some();
+                        if let Some(connected) = connected_peripherals.remove(&address) {
+                            let _ = connected.peripheral.disconnect().await;
+                            _ = tx_clone_2.send(BleMessage::DeviceDisconnected(address));
+                        } else if was_reconnecting {
+                            _ = tx_clone_2.send(BleMessage::DeviceDisconnected(address));
                         }
-                        BleCommand::SendData(data) => {
-                            if let Some(peripheral) = &connected_peripheral {
-                                let services = peripheral.services();
-                                for service in services {
-                                    if service.uuid.to_string() != LOVENSE_SERVICE_UUID {
-                                        continue;
-                                    }
-
-                                    for characteristic in service.characteristics {
-                                        if characteristic.uuid.to_string() != LOVENSE_TX_UUID {
-                                            continue;
-                                        }
-
-                                        _ = peripheral.write(&characteristic, &data, WriteType::WithoutResponse).await;
-                                    }
-                                }
+                    }
+                    BleCommand::SendData(address, data) => {
+                        if let Some(connected) = connected_peripherals.get(&address) {
+                            Self::write_data(connected, &data).await;
+                        }
+                    }
+                    BleCommand::SetDiagnostics(enabled) => diagnostics.store(enabled, Ordering::Relaxed),
+                    BleCommand::Inspect(address, adapter_name) => {
+                        // Connecting may take until the connect timeout, the connected toys are served meanwhile
+                        let peripheral = connected_peripherals.get(&address).map(|connected| connected.peripheral.clone());
+                        let adapter = Self::select_adapter(&named_adapters, adapter_name.as_deref()).clone();
+                        let gui_tx = tx_clone_2.clone();
+                        tokio::spawn(async move {
+                            let services = match peripheral {
+                                Some(peripheral) => peripheral.discover_services().await,
+                                None => Self::inspect_peripheral(&adapter, &address).await,
+                            };
+                            _ = gui_tx.send(BleMessage::GattServices(address, services.map_err(|error| error.to_string())));
+                        });
+                    }
+                    BleCommand::SetIntensity(address, write) => {
+                        // A write for every motor replaces the per-motor ones and vice versa, like the stored payloads
+                        let superseded = pending_writes.keys()
+                            .filter(|(pending_address, motor)| *pending_address == address && (*motor == write.motor || *motor == 0 || write.motor == 0))
+                            .cloned()
+                            .collect::<Vec<(String, u8)>>();
+                        for key in superseded {
+                            if let Some(superseded) = pending_writes.remove(&key) {
+                                superseded.stats.lock().expect("Could not lock").dropped += 1;
                             }
                         }
+                        pending_writes.insert((address, write.motor), write);
                     }
                 }
             }
-        });
+
+            // Only the latest intensity of each device is written, at most once per its minimum interval
+            let due_writes = pending_writes.iter()
+                .filter(|((address, _), write)| connected_peripherals.get(address).is_none_or(|connected| connected.write_delay(write.timing.min_interval).is_zero()))
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<(String, u8)>>();
+            for key in due_writes {
+                let Some(write) = pending_writes.remove(&key) else {
+                    continue;
+                };
+
+                match connected_peripherals.get_mut(&key.0) {
+                    Some(connected) => {
+                        let written = Self::write_data(connected, &write.data).await;
+                        connected.store_intensity(write.motor, write.data);
+                        connected.keepalive = write.timing.keepalive;
+                        let mut stats = write.stats.lock().expect("Could not lock");
+                        match written {
+                            true => stats.written += 1,
+                            false => stats.failed += 1,
+                        }
+                    }
+                    None => write.stats.lock().expect("Could not lock").dropped += 1,
+                }
+            }
+
+            // Toys that stop on their own without fresh commands get the current levels again
+            for connected in connected_peripherals.values_mut().filter(|connected| connected.keepalive_delay().is_some_and(|delay| delay.is_zero())) {
+                for data in connected.intensity_payloads.values() {
+                    Self::write_data(connected, data).await;
+                }
+                connected.last_intensity_write.replace(Instant::now());
+            }
+        }
+    }
+
+    /// Adapter with the given name, or the first adapter if it is not available
+    fn select_adapter<'a, A: BleAdapter>(adapters: &'a [(String, A)], name: Option<&str>) -> &'a A {
+        let (_, adapter) = adapters
+            .iter()
+            .find(|(adapter_name, _)| Some(adapter_name.as_str()) == name)
+            .unwrap_or(&adapters[0]);
+        adapter
+    }
+
+    /// Connects to a discovered peripheral and prepares it for writing
+    async fn connect_peripheral<A: BleAdapter>(adapters: &[(String, A)], adapter_name: Option<&str>, address: &str, protocol: &GattProtocol, gui_tx: &Sender<BleMessage>) -> anyhow::Result<ConnectedPeripheral<A::Peripheral>> {
+        let adapter = Self::select_adapter(adapters, adapter_name);
+        let Some(peripheral) = adapter.peripheral(address).await? else {
+            anyhow::bail!("Device is out of range");
+        };
+
+        peripheral.connect().await?;
+        let services = match peripheral.discover_services().await {
+            Ok(services) => services,
+            Err(error) => {
+                _ = peripheral.disconnect().await;
+                anyhow::bail!("Service discovery failed: {}", error);
+            }
+        };
+
+        let service_uuids = services.iter().map(|service| service.uuid).collect::<Vec<Uuid>>();
+        let Some(endpoints) = protocol.endpoints(&service_uuids) else {
+            _ = peripheral.disconnect().await;
+            anyhow::bail!("No known {} service", protocol.identifier);
+        };
+
+        // Characteristics missing from the device config are found by their properties
+        let characteristics = services
+            .into_iter()
+            .filter(|service| service.uuid == endpoints.service)
+            .flat_map(|service| service.characteristics)
+            .collect::<Vec<Characteristic>>();
+        let tx = Self::find_characteristic(&characteristics, endpoints.tx, CharPropFlags::WRITE_WITHOUT_RESPONSE | CharPropFlags::WRITE);
+        let rx = Self::find_characteristic(&characteristics, endpoints.rx, CharPropFlags::NOTIFY);
+        let Some(tx) = tx else {
+            _ = peripheral.disconnect().await;
+            anyhow::bail!("No writable characteristic");
+        };
+
+        // Some firmwares only accept acknowledged writes
+        let write_type = if tx.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE) {
+            WriteType::WithoutResponse
+        } else {
+            WriteType::WithResponse
+        };
+
+        let mut connected = ConnectedPeripheral {
+            peripheral,
+            address: address.into(),
+            gui_tx: gui_tx.clone(),
+            health: Arc::new(Mutex::new(CommandHealth::default())),
+            protocol: protocol.clone(),
+            adapter: adapter_name.map(String::from),
+            tx,
+            rx,
+            write_type,
+            notification_task: None,
+            last_intensity_write: None,
+            intensity_payloads: BTreeMap::new(),
+            keepalive: None,
+        };
+        if protocol.family == ProtocolFamily::Lovense {
+            connected.notification_task = Self::subscribe_notifications(&connected).await;
+            Self::write_data(&connected, &LovenseCommand::DeviceType.encode()).await;
+            Self::write_data(&connected, &LovenseCommand::Battery.encode()).await;
+        }
+
+        Ok(connected)
+    }
+
+    /// Services of a device that is not connected, the connection is closed again afterwards
+    async fn inspect_peripheral<A: BleAdapter>(adapter: &A, address: &str) -> anyhow::Result<Vec<Service>> {
+        let Some(peripheral) = adapter.peripheral(address).await? else {
+            anyhow::bail!("Device is out of range");
+        };
+
+        peripheral.connect().await?;
+        let services = peripheral.discover_services().await;
+        _ = peripheral.disconnect().await;
+        services
+    }
+
+    /// Exponential backoff between reconnection attempts
+    fn reconnect_delay(attempt: u32) -> Duration {
+        (RECONNECT_BASE_DELAY * 2u32.saturating_pow(attempt.saturating_sub(1))).min(RECONNECT_MAX_DELAY)
+    }
+
+    /// Characteristic with the configured UUID, otherwise the first one with any of the given properties
+    fn find_characteristic(characteristics: &[Characteristic], uuid: Option<Uuid>, properties: CharPropFlags) -> Option<Characteristic> {
+        uuid.and_then(|uuid| characteristics.iter().find(|characteristic| characteristic.uuid == uuid))
+            .or_else(|| characteristics.iter().find(|characteristic| characteristic.properties.intersects(properties)))
+            .cloned()
+    }
+
+    /// Writes to the TX characteristic and reports changes of the command health to the GUI
+    async fn write_data<P: BlePeripheral>(connected: &ConnectedPeripheral<P>, data: &[u8]) -> bool {
+        let result = connected.peripheral.write(&connected.tx, data, connected.write_type).await;
+        if let Err(error) = &result {
+            eprintln!("Failed to write to {}: {}", connected.address, error);
+        }
+
+        let mut health = connected.health.lock().expect("Could not lock");
+        match &result {
+            // Only toys with a subscribed RX characteristic answer commands
+            Ok(()) => {
+                health.write_error.take();
+                if connected.notification_task.is_some() {
+                    health.unanswered += 1;
+                }
+            }
+            Err(error) => _ = health.write_error.replace(error.to_string()),
+        }
+        if let Some(error) = health.update() {
+            _ = connected.gui_tx.send(BleMessage::CommandStatus(connected.address.clone(), error));
+        }
+
+        result.is_ok()
+    }
+
+    async fn subscribe_notifications<P: BlePeripheral>(connected: &ConnectedPeripheral<P>) -> Option<JoinHandle<()>> {
+        let characteristic = connected.rx.clone()?;
+        let address = connected.address.clone();
+        let gui_tx = connected.gui_tx.clone();
+        let health = connected.health.clone();
+
+        let mut notifications = match connected.peripheral.subscribe(&characteristic).await {
+            Ok(notifications) => notifications,
+            Err(error) => {
+                eprintln!("Failed to subscribe to notifications: {}", error);
+                return None;
+            }
+        };
+
+        Some(tokio::spawn(async move {
+            while let Some(value) = notifications.next().await {
+                let reply = LovenseReply::parse(&value);
+                if let Some(error) = health.lock().expect("Could not lock").answered(reply != LovenseReply::Error) {
+                    _ = gui_tx.send(BleMessage::CommandStatus(address.clone(), error));
+                }
+
+                match reply {
+                    LovenseReply::Battery(level) => {
+                        _ = gui_tx.send(BleMessage::BatteryLevel(address.clone(), level));
+                    }
+                    LovenseReply::DeviceType(device_type) => {
+                        _ = gui_tx.send(BleMessage::DeviceType(address.clone(), device_type));
+                    }
+                    _ => continue,
+                }
+            }
+        }))
     }
 }
 
@@ -235,22 +548,534 @@ impl BluetoothGattService {
 pub enum BleMessage {
     AdapterInitialized,
     AdapterError(String),
+    AdapterFailed(String, String), // adapter name, error, the remaining adapters keep working
     DeviceDiscovered(BluetoothGattDevice),
     DeviceConnecting(String),
     DeviceConnected(String),
     DeviceDisconnected(String),
+    Reconnecting(String, u32), // address, attempt
+    ConnectFailed(String, String), // address, error
+    Advertisement(String, Advertisement), // adapter name, only sent while diagnostics are enabled
+    GattServices(String, Result<Vec<Service>, String>), // address, services or why they could not be listed
+    CommandStatus(String, Option<String>), // address, why commands are failing, None once they succeed again
+    AdaptersFound(Vec<String>),
+    BatteryLevel(String, u8), // address, percent
+    DeviceType(String, LovenseDeviceType),
 }
 
 // Commands sent from GUI thread to BLE thread
 #[derive(Debug)]
 pub enum BleCommand {
-    Connect(String), // address
-    Disconnect,
-    SendData(Vec<u8>),
+    Connect(String, GattProtocol, Option<String>), // address, protocol matched during discovery, adapter name
+    Disconnect(String), // address
+    SendData(String, Vec<u8>), // address, data
+    SetIntensity(String, PendingWrite), // address, write that replaces any pending one
+    SetDiagnostics(bool),
+    Inspect(String, Option<String>), // address, adapter name
 }
 
 #[derive(Debug, Clone)]
 pub struct BluetoothGattDevice {
     pub device_address: String,
     pub device_name: Option<String>,
-}
\ No newline at end of file
+    pub rssi: Option<i16>,
+    pub protocol: GattProtocol,
+}
+
+struct ConnectedPeripheral<P> {
+    peripheral: P,
+    address: String,
+    gui_tx: Sender<BleMessage>,
+    health: Arc<Mutex<CommandHealth>>,
+    protocol: GattProtocol,
+    adapter: Option<String>,
+    tx: Characteristic,
+    rx: Option<Characteristic>,
+    write_type: WriteType,
+    notification_task: Option<JoinHandle<()>>,
+    last_intensity_write: Option<Instant>,
+    intensity_payloads: BTreeMap<u8, Vec<u8>>, // motor, last payload written to it
+    keepalive: Option<Duration>,
+}
+
+impl<P> ConnectedPeripheral<P> {
+    /// Time left until the next intensity write is allowed
+    fn write_delay(&self, min_interval: Duration) -> Duration {
+        self.last_intensity_write.map_or(Duration::ZERO, |last_write| min_interval.saturating_sub(last_write.elapsed()))
+    }
+
+    /// Time left until the current levels have to be re-sent, `None` without keepalive or levels
+    fn keepalive_delay(&self) -> Option<Duration> {
+        let keepalive = self.keepalive?;
+        let last_write = self.last_intensity_write.filter(|_| !self.intensity_payloads.is_empty())?;
+        Some(keepalive.saturating_sub(last_write.elapsed()))
+    }
+
+    /// Remembers a written payload, a payload for every motor replaces the per-motor ones and vice versa
+    fn store_intensity(&mut self, motor: u8, data: Vec<u8>) {
+        match motor {
+            0 => self.intensity_payloads.clear(),
+            _ => _ = self.intensity_payloads.remove(&0),
+        }
+        self.intensity_payloads.insert(motor, data);
+        self.last_intensity_write.replace(Instant::now());
+    }
+}
+
+impl<P> Drop for ConnectedPeripheral<P> {
+    fn drop(&mut self) {
+        if let Some(task) = self.notification_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Write results and acknowledgements of the commands sent to a peripheral
+#[derive(Debug, Default)]
+struct CommandHealth {
+    write_error: Option<String>,
+    unanswered: u32,
+    rejected: bool,
+    reported: Option<String>,
+}
+
+impl CommandHealth {
+    fn error(&self) -> Option<String> {
+        if let Some(error) = &self.write_error {
+            return Some(format!("Write failed: {}", error));
+        }
+
+        if self.rejected {
+            Some("Command rejected by the toy".into())
+        } else if self.unanswered >= MAX_UNANSWERED_COMMANDS {
+            Some("Commands are not acknowledged".into())
+        } else {
+            None
+        }
+    }
+
+    /// Records a reply of the toy, returns the new error if it changed
+    fn answered(&mut self, accepted: bool) -> Option<Option<String>> {
+        self.unanswered = 0;
+        self.rejected = !accepted;
+        self.update()
+    }
+
+    /// Returns the current error if it changed since it was last reported
+    fn update(&mut self) -> Option<Option<String>> {
+        let error = self.error();
+        if error == self.reported {
+            return None;
+        }
+
+        self.reported = error.clone();
+        Some(error)
+    }
+}
+
+/// Latest intensity payload of a device that has not been written yet
+#[derive(Debug)]
+pub struct PendingWrite {
+    motor: u8, // 0 drives every motor
+    data: Vec<u8>,
+    timing: WriteTiming,
+    stats: SharedWriteStats,
+}
+
+/// Peripheral that lost its link unexpectedly and is being reconnected
+struct PendingReconnect {
+    protocol: GattProtocol,
+    adapter: Option<String>,
+    attempt: u32,
+    next_attempt: Instant,
+}
+
+/// Output handle for a single discovered GATT device, commands are forwarded to the BLE thread
+pub struct GattOutputDevice {
+    device: BluetoothGattDevice,
+    ble_tx: Sender<BleCommand>,
+    last_level: Option<u8>,
+    last_motor_levels: HashMap<u8, u8>,
+    adapter: Option<String>,
+    write_timing: WriteTiming,
+    write_stats: SharedWriteStats,
+}
+
+impl GattOutputDevice {
+    fn send_intensity(&self, motor: u8, data: Vec<u8>) -> anyhow::Result<()> {
+        let write = PendingWrite {
+            motor,
+            data,
+            timing: self.write_timing,
+            stats: self.write_stats.clone(),
+        };
+        self.ble_tx.send(BleCommand::SetIntensity(self.device.device_address.clone(), write))?;
+        Ok(())
+    }
+}
+
+impl OutputDevice for GattOutputDevice {
+    fn name(&self) -> String {
+        self.device.device_name.clone().unwrap_or(self.device.device_address.clone())
+    }
+
+    fn identifier(&self) -> String {
+        self.device.device_address.clone()
+    }
+
+    fn ble_address(&self) -> Option<String> {
+        Some(self.device.device_address.clone())
+    }
+
+    fn protocol(&self) -> Option<String> {
+        Some(self.device.protocol.identifier.clone())
+    }
+
+    fn steps(&self) -> u8 {
+        self.device.protocol.family.steps()
+    }
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        self.reset_levels();
+        let command = BleCommand::Connect(self.device.device_address.clone(), self.device.protocol.clone(), self.adapter.clone());
+        self.ble_tx.send(command)?;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.ble_tx.send(BleCommand::Disconnect(self.device.device_address.clone()))?;
+        Ok(())
+    }
+
+    fn set_adapter(&mut self, adapter: Option<String>) {
+        self.adapter = adapter;
+    }
+
+    fn set_write_timing(&mut self, timing: WriteTiming) {
+        self.write_timing = timing;
+    }
+
+    fn write_stats(&self) -> Option<WriteStats> {
+        Some(*self.write_stats.lock().expect("Could not lock"))
+    }
+
+    fn reset_levels(&mut self) {
+        self.last_level.take();
+        self.last_motor_levels.clear();
+    }
+
+    fn set_intensity(&mut self, intensity: f32) -> anyhow::Result<()> {
+        let level = intensity_to_level(intensity, self.steps());
+        if self.last_level == Some(level) {
+            return Ok(());
+        }
+
+        self.last_level.replace(level);
+        self.last_motor_levels.clear();
+        self.send_intensity(0, self.device.protocol.family.encode_vibrate(level))
+    }
+
+    fn set_motor_intensity(&mut self, motor: u8, intensity: f32) -> anyhow::Result<()> {
+        if !self.supports_lovense_commands() {
+            return self.set_intensity(intensity);
+        }
+
+        let level = intensity_to_level(intensity, self.steps());
+        if self.last_motor_levels.get(&motor) == Some(&level) {
+            return Ok(());
+        }
+
+        self.last_motor_levels.insert(motor, level);
+        self.last_level.take();
+        self.send_intensity(motor, LovenseCommand::VibrateMotor(motor, level).encode())
+    }
+
+    fn supports_lovense_commands(&self) -> bool {
+        self.device.protocol.family == ProtocolFamily::Lovense
+    }
+
+    fn send_lovense_command(&mut self, command: LovenseCommand) -> anyhow::Result<()> {
+        if !self.supports_lovense_commands() {
+            return Err(anyhow::anyhow!("Device does not support Lovense commands"));
+        }
+
+        let address = self.device.device_address.clone();
+        self.ble_tx.send(BleCommand::SendData(address, command.encode()))?;
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bluetooth::fake::{FakeAdapter, FakeCentral, FakePeripheral};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Service whose thread runs on the fake central instead of btleplug
+    fn start_fake(adapters: Vec<FakeAdapter>) -> BluetoothGattService {
+        let (gui_tx, ble_rx) = channel::<BleMessage>();
+        let (ble_tx, gui_rx) = channel::<BleCommand>();
+
+        let device_config = DeviceConfig::builtin();
+        let thread_config = device_config.clone();
+        thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(BluetoothGattService::run(FakeCentral::new(adapters), gui_tx, gui_rx, thread_config));
+        });
+
+        BluetoothGattService {
+            ble_rx: Some(ble_rx),
+            ble_tx: Some(ble_tx),
+            device_config,
+            thread_running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// First message the filter accepts, every other message is skipped
+    fn wait_for<T>(service: &mut BluetoothGattService, mut filter: impl FnMut(BleMessage) -> Option<T>) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            match service.fetch_ble_message() {
+                Some(message) => if let Some(value) = filter(message) {
+                    return value;
+                },
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+
+        panic!("Expected message was not sent");
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition() {
+            assert!(Instant::now() < deadline, "Condition was not met");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn discover_toy(service: &mut BluetoothGattService, toy: &FakePeripheral) -> GattOutputDevice {
+        let address = toy.address();
+        let discovered = wait_for(service, |message| match message {
+            BleMessage::DeviceDiscovered(device) if device.device_address == address => Some(device),
+            _ => None,
+        });
+
+        service.create_device(discovered).unwrap()
+    }
+
+    fn connect_toy(service: &mut BluetoothGattService, toy: &FakePeripheral) -> GattOutputDevice {
+        let mut device = discover_toy(service, toy);
+        device.connect().unwrap();
+        wait_for(service, |message| matches!(message, BleMessage::DeviceConnected(_)).then_some(()));
+        device
+    }
+
+    #[test]
+    fn connects_and_writes_to_lovense_toy() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:01");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+
+        let mut device = discover_toy(&mut service, &toy);
+        device.connect().unwrap();
+
+        // The toy may answer before the connection is reported
+        let (mut connected, mut battery) = (false, None);
+        wait_for(&mut service, |message| {
+            match message {
+                BleMessage::DeviceConnected(_) => connected = true,
+                BleMessage::BatteryLevel(_, level) => battery = Some(level),
+                _ => {}
+            }
+            (connected && battery.is_some()).then_some(())
+        });
+        assert_eq!(battery, Some(85));
+        assert_eq!(toy.take_written(), vec![b"DeviceType;".to_vec(), b"Battery;".to_vec()]);
+
+        device.set_intensity(0.5).unwrap();
+        wait_until(|| device.write_stats().unwrap().written == 1);
+        assert_eq!(toy.take_written(), vec![b"Vibrate:10;".to_vec()]);
+    }
+
+    #[test]
+    fn skips_failing_adapters() {
+        let broken = FakeAdapter::new("hci0");
+        broken.fail_scan();
+        let adapter = FakeAdapter::new("hci1");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:07");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![broken, adapter]);
+
+        let failed = wait_for(&mut service, |message| match message {
+            BleMessage::AdapterFailed(adapter, _) => Some(adapter),
+            _ => None,
+        });
+        assert_eq!(failed, "hci0");
+
+        // The remaining adapter still discovers and connects toys
+        connect_toy(&mut service, &toy);
+    }
+
+    #[test]
+    fn reports_connection_failures() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:02");
+        toy.fail_connects(1);
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+
+        let mut device = discover_toy(&mut service, &toy);
+        device.connect().unwrap();
+
+        let error = wait_for(&mut service, |message| match message {
+            BleMessage::ConnectFailed(_, error) => Some(error),
+            _ => None,
+        });
+        assert_eq!(error, "Connection refused");
+        assert!(!toy.is_linked());
+    }
+
+    #[test]
+    fn reconnects_after_link_loss() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:03");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+        let _device = connect_toy(&mut service, &toy);
+
+        // The first attempt fails, the second one follows after the backoff delay
+        toy.fail_connects(1);
+        toy.drop_link();
+        wait_for(&mut service, |message| matches!(message, BleMessage::DeviceDisconnected(_)).then_some(()));
+        wait_for(&mut service, |message| matches!(message, BleMessage::Reconnecting(_, 1)).then_some(()));
+        wait_for(&mut service, |message| matches!(message, BleMessage::Reconnecting(_, 2)).then_some(()));
+        wait_for(&mut service, |message| matches!(message, BleMessage::DeviceConnected(_)).then_some(()));
+        assert!(toy.is_linked());
+    }
+
+    #[test]
+    fn reports_failing_commands() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:04");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+        let mut device = connect_toy(&mut service, &toy);
+
+        toy.mute();
+        for _ in 0..MAX_UNANSWERED_COMMANDS {
+            device.send_lovense_command(LovenseCommand::Battery).unwrap();
+        }
+        let error = wait_for(&mut service, |message| match message {
+            BleMessage::CommandStatus(_, error) => error,
+            _ => None,
+        });
+        assert_eq!(error, "Commands are not acknowledged");
+
+        toy.fail_writes(true);
+        device.set_intensity(1.0).unwrap();
+        let error = wait_for(&mut service, |message| match message {
+            BleMessage::CommandStatus(_, error) => error,
+            _ => None,
+        });
+        assert_eq!(error, "Write failed: Write rejected");
+        wait_until(|| device.write_stats().unwrap().failed == 1);
+    }
+
+    #[test]
+    fn inspects_devices_without_keeping_them_connected() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:06");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+        discover_toy(&mut service, &toy);
+
+        service.inspect(&toy.address(), None);
+        let services = wait_for(&mut service, |message| match message {
+            BleMessage::GattServices(_, services) => Some(services),
+            _ => None,
+        });
+        assert_eq!(services.unwrap()[0].characteristics.len(), 2);
+        assert!(!toy.is_linked());
+    }
+
+    #[test]
+    fn keeps_writing_while_inspecting() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:08");
+        let unresponsive = FakePeripheral::lovense("AA:BB:CC:DD:EE:09");
+        adapter.advertise(&toy);
+        adapter.advertise(&unresponsive);
+        let mut service = start_fake(vec![adapter]);
+        let mut device = connect_toy(&mut service, &toy);
+        toy.take_written();
+
+        let release = unresponsive.hold_connect();
+        service.inspect(&unresponsive.address(), None);
+        device.set_intensity(0.5).unwrap();
+        wait_until(|| device.write_stats().unwrap().written == 1);
+        assert_eq!(toy.take_written(), vec![b"Vibrate:10;".to_vec()]);
+
+        release.send(()).unwrap();
+        let services = wait_for(&mut service, |message| match message {
+            BleMessage::GattServices(_, services) => Some(services),
+            _ => None,
+        });
+        assert!(services.is_ok());
+    }
+
+    #[test]
+    fn replaces_pending_writes_of_other_motors() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:08");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+        let mut device = connect_toy(&mut service, &toy);
+        toy.take_written();
+
+        device.set_write_timing(WriteTiming {
+            min_interval: Duration::from_millis(300),
+            keepalive: None,
+        });
+        device.set_intensity(0.5).unwrap();
+        wait_until(|| device.write_stats().unwrap().written == 1);
+
+        // Both kinds queue up within one interval, only the newest one is written
+        device.set_intensity(0.25).unwrap();
+        device.set_motor_intensity(1, 0.75).unwrap();
+        wait_until(|| device.write_stats().unwrap().written == 2);
+
+        device.set_motor_intensity(2, 0.25).unwrap();
+        device.set_intensity(1.0).unwrap();
+        wait_until(|| device.write_stats().unwrap().written == 3);
+
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(toy.take_written(), vec![b"Vibrate:10;".to_vec(), b"Vibrate1:15;".to_vec(), b"Vibrate:20;".to_vec()]);
+        assert_eq!(device.write_stats().unwrap().dropped, 2);
+    }
+
+    #[test]
+    fn resends_levels_as_keepalive() {
+        let adapter = FakeAdapter::new("hci0");
+        let toy = FakePeripheral::lovense("AA:BB:CC:DD:EE:05");
+        adapter.advertise(&toy);
+        let mut service = start_fake(vec![adapter]);
+        let mut device = connect_toy(&mut service, &toy);
+        toy.take_written();
+
+        device.set_write_timing(WriteTiming {
+            min_interval: Duration::ZERO,
+            keepalive: Some(Duration::from_millis(20)),
+        });
+        device.set_intensity(0.25).unwrap();
+
+        let mut written = Vec::new();
+        wait_until(|| {
+            written.extend(toy.take_written());
+            written.len() >= 3
+        });
+        assert!(written.iter().all(|data| data == b"Vibrate:5;"));
+        assert_eq!(device.write_stats().unwrap().written, 1);
+    }
+}
@@ -1,22 +1,118 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::bluetooth::adv_protocol::{protocol, CLASSIC_PROTOCOL};
+use crate::device::{intensity_to_level, OutputDevice, SharedWriteStats, WriteStats, WriteTiming};
+
+pub const DEFAULT_COMPANY_ID: u16 = 0xFFF0;
+pub const DEFAULT_ADDRESS: [u8; 5] = [0x77, 0x62, 0x4d, 0x53, 0x45];
+pub const MIN_ADV_INTERVAL_MS: u16 = 20; // lowest interval allowed for connectable advertising
+pub const MAX_ADV_INTERVAL_MS: u16 = 10240;
+pub const DEFAULT_ADV_INTERVAL_MS: u16 = MIN_ADV_INTERVAL_MS;
+/// Every level change restarts the advertisement, the toy needs a few packets to pick it up
+const MIN_LEVEL_DURATION: Duration = Duration::from_millis(250);
+
+/// Advertisement based toy, a toy only reacts to payloads carrying its own address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GenericToy {
+    pub identifier: String, // "generic" for the first toy, "generic-N" for further ones
+    pub name: String,
+    pub address: [u8; 5],
+    pub company_id: u16,
+    #[serde(default = "GenericToy::default_protocol")]
+    pub protocol: String, // ADV protocol identifier
+}
+
+impl Default for GenericToy {
+    fn default() -> Self {
+        Self {
+            identifier: "generic".into(),
+            name: "Generic Device".into(),
+            address: DEFAULT_ADDRESS,
+            company_id: DEFAULT_COMPANY_ID,
+            protocol: Self::default_protocol(),
+        }
+    }
+}
+
+impl GenericToy {
+    fn default_protocol() -> String {
+        CLASSIC_PROTOCOL.into()
+    }
+
+    /// Next free identifier, the first toy keeps the plain "generic" used by older settings
+    pub fn next_identifier(toys: &[GenericToy]) -> String {
+        (1..)
+            .map(|index| match index {
+                1 => "generic".to_string(),
+                _ => format!("generic-{}", index),
+            })
+            .find(|identifier| toys.iter().all(|toy| &toy.identifier != identifier))
+            .unwrap()
+    }
+}
+
+/// What a generic toy is told to do, a plain speed level or one of its built-in programs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvOutput {
+    Speed(u8),
+    Pattern(usize), // index into the patterns of the toy's protocol
+}
+
+impl AdvOutput {
+    fn is_off(&self) -> bool {
+        *self == AdvOutput::Speed(0)
+    }
+}
 
-const COMPANY_ID: u16 = 0xFFF0;
-const RAW_ADDRESS: [u8; 5] = [0x77, 0x62, 0x4d, 0x53, 0x45];
+/// Radio parameters shared by all generic toys, `tx_power` of `None` uses the adapter maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvOptions {
+    pub interval: Duration,
+    pub tx_power: Option<i16>,
+}
+
+impl Default for AdvOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(DEFAULT_ADV_INTERVAL_MS as u64),
+            tx_power: None,
+        }
+    }
+}
 
 pub struct BluetoothGenericService {
-    pub gui_tx: Option<Sender<u8>>,
-    last_speed: u8,
+    pub gui_tx: Option<Sender<AdvCommand>>,
+    adapter: Option<String>,
+    adapters: Arc<Mutex<Vec<String>>>,
+    idle_stop: Option<Duration>,
+    options: AdvOptions,
+    learning: bool,
+    learn_tx: Sender<(u16, Vec<u8>)>,
+    learn_rx: Receiver<(u16, Vec<u8>)>, // manufacturer data of stock remotes while learning
+    write_stats: Arc<Mutex<HashMap<String, SharedWriteStats>>>, // by toy identifier
     thread_running: Arc<AtomicBool>,
 }
 
 impl BluetoothGenericService {
-    pub fn new() -> Self {
+    /// Advertises on the adapter with the given name, or the default adapter if `None`
+    pub fn new(adapter: Option<String>) -> Self {
+        let (learn_tx, learn_rx) = channel();
         let mut result = Self {
             gui_tx: None,
-            last_speed: 0,
+            adapter,
+            adapters: Arc::new(Mutex::new(Vec::new())),
+            idle_stop: None,
+            options: AdvOptions::default(),
+            learning: false,
+            learn_tx,
+            learn_rx,
+            write_stats: Arc::new(Mutex::new(HashMap::new())),
             thread_running: Arc::new(AtomicBool::new(false)),
         };
 
@@ -30,32 +126,73 @@ impl BluetoothGenericService {
             return;
         }
 
-        let (gui_tx, ble_rx) = channel::<u8>();
+        let (gui_tx, ble_rx) = channel::<AdvCommand>();
+        _ = gui_tx.send(AdvCommand::SetIdleStop(self.idle_stop));
+        _ = gui_tx.send(AdvCommand::SetOptions(self.options));
+        _ = gui_tx.send(AdvCommand::SetLearning(self.learning));
 
         self.gui_tx.replace(gui_tx);
 
         let thread_running = self.thread_running.clone();
+        let adapter = self.adapter.clone();
+        let adapters = self.adapters.clone();
+        let learn_tx = self.learn_tx.clone();
+        let write_stats = self.write_stats.clone();
         thread::spawn(move || {
             thread_running.store(true, Ordering::Relaxed);
-            Self::ble_thread(ble_rx);
+            Self::ble_thread(ble_rx, adapter, adapters, learn_tx, write_stats);
             thread_running.store(false, Ordering::Relaxed);
         });
     }
 
-    fn speed_to_command(speed: u8) -> Command {
-        match speed {
-            1 => Command::Raw([0xF4, 0x00, 0x00]),
-            2 => Command::Raw([0xF7, 0x00, 0x00]),
-            3 => Command::Raw([0xF6, 0x00, 0x00]),
-            4 => Command::Raw([0xF1, 0x00, 0x00]),
-            5 => Command::Raw([0xF3, 0x00, 0x00]),
-            6 => Command::Raw([0xE7, 0x00, 0x00]),
-            7 => Command::Raw([0xE6, 0x00, 0x00]),
-            _ => Command::Raw([0xE5, 0x00, 0x00]),
+    /// Names of the adapters that can advertise, known once the service thread is initialized
+    pub fn adapters(&self) -> Vec<String> {
+        self.adapters.lock().expect("Could not lock").clone()
+    }
+
+    /// Moves advertising to another adapter, `None` picks the default one
+    pub fn set_adapter(&mut self, adapter: Option<String>) {
+        self.adapter = adapter.clone();
+        if let Some(gui_tx) = &self.gui_tx {
+            _ = gui_tx.send(AdvCommand::SelectAdapter(adapter));
+        }
+    }
+
+    /// Stops advertising after the toy was at level 0 for this long, `None` advertises forever
+    pub fn set_idle_stop(&mut self, idle_stop: Option<Duration>) {
+        self.idle_stop = idle_stop;
+        if let Some(gui_tx) = &self.gui_tx {
+            _ = gui_tx.send(AdvCommand::SetIdleStop(idle_stop));
+        }
+    }
+
+    /// Changes the advertising interval and TX power, current advertisements are restarted with them
+    pub fn set_options(&mut self, options: AdvOptions) {
+        self.options = options;
+        if let Some(gui_tx) = &self.gui_tx {
+            _ = gui_tx.send(AdvCommand::SetOptions(options));
+        }
+    }
+
+    /// Scans for stock remotes on the advertising adapter, their payloads are returned by `fetch_learned`
+    pub fn set_learning(&mut self, learning: bool) {
+        self.learning = learning;
+        if let Some(gui_tx) = &self.gui_tx {
+            _ = gui_tx.send(AdvCommand::SetLearning(learning));
         }
     }
 
-    fn ble_thread(ble_rx: Receiver<u8>) {
+    /// Company ID and manufacturer data of the next advertisement found while learning
+    pub fn fetch_learned(&self) -> Option<(u16, Vec<u8>)> {
+        self.learn_rx.try_recv().ok()
+    }
+
+    /// Builds the full manufacturer data payload the classic protocol advertises for the given speed level
+    pub fn speed_to_payload(address: &[u8; 5], speed: u8) -> Vec<u8> {
+        protocol(CLASSIC_PROTOCOL).payload(address, AdvOutput::Speed(speed))
+    }
+
+    fn ble_thread(ble_rx: Receiver<AdvCommand>, adapter: Option<String>, adapters: Arc<Mutex<Vec<String>>>, learn_tx: Sender<(u16, Vec<u8>)>, write_stats: Arc<Mutex<HashMap<String, SharedWriteStats>>>) {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
             let mut advertiser = {
@@ -65,64 +202,343 @@ impl BluetoothGenericService {
                 #[cfg(target_os = "windows")]
                 { crate::bluetooth::adv_windows::ble_adv::BleAdvertiserWindows::new() }
             };
-            if let Err(error) = advertiser.init().await {
+            if let Err(error) = advertiser.init(adapter.as_deref()).await {
                 eprintln!("{}", error);
                 return;
             }
 
-            loop {
-                if let Ok(speed) = ble_rx.recv() {
-                    let command = Self::speed_to_command(speed);
+            match advertiser.adapters().await {
+                Ok(names) => *adapters.lock().expect("Could not lock") = names,
+                Err(error) => eprintln!("Failed to list advertising adapters: {}", error),
+            }
 
-                    let command = BleUtil::get_ble_command(&RAW_ADDRESS, command);
-                    let mut final_command = vec![0x02, 0x01, 0x06];
-                    final_command.extend(command);
+            let mut idle_stop = None;
+            let mut options = AdvOptions::default();
+            let mut learning = false;
+            let mut toys: HashMap<String, (GenericToy, AdvScheduler)> = HashMap::new();
+            loop {
+                // The toy whose advertisement is due first
+                let now = Instant::now();
+                let action = toys
+                    .iter()
+                    .filter_map(|(identifier, (_, scheduler))| scheduler.next_action(now).map(|(action, due)| (identifier.clone(), action, due)))
+                    .min_by_key(|(_, _, due)| *due);
+
+                if let Some((identifier, action, due)) = &action && *due <= Instant::now() {
+                    let Some((toy, scheduler)) = toys.get_mut(identifier) else {
+                        continue;
+                    };
+
+                    match *action {
+                        AdvAction::Send(output) => {
+                            let final_command = protocol(&toy.protocol).payload(&toy.address, output);
+
+                            // Failed advertisements are not retried until the next speed or keepalive
+                            let result = advertiser.send(identifier, toy.company_id, &final_command, options).await;
+                            if scheduler.sent(output, Instant::now()) && let Some(write_stats) = write_stats.lock().expect("Could not lock").get(identifier) {
+                                let mut write_stats = write_stats.lock().expect("Could not lock");
+                                match &result {
+                                    Ok(()) => write_stats.written += 1,
+                                    Err(_) => write_stats.failed += 1,
+                                }
+                            }
+                            if let Err(error) = result {
+                                eprintln!("Failed to advertise {:?} for {}: {}", output, toy.name, error);
+                            }
+                        }
+                        AdvAction::Stop => {
+                            _ = advertiser.stop(identifier).await;
+                            scheduler.stopped();
+                        }
+                    }
+                    continue;
+                }
 
-                    advertiser.send(COMPANY_ID, &final_command).await.unwrap();
+                let command = match action {
+                    Some((_, _, due)) => match ble_rx.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                        Ok(command) => command,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match ble_rx.recv() {
+                        Ok(command) => command,
+                        Err(_) => break,
+                    },
+                };
+
+                let mut commands = vec![command];
+                commands.extend(ble_rx.try_iter());
+                let mut restart = false;
+                for command in commands {
+                    match command {
+                        AdvCommand::SetOutput(toy, output, timing) => {
+                            let (current_toy, scheduler) = toys.entry(toy.identifier.clone()).or_insert_with(|| {
+                                (toy.clone(), AdvScheduler { idle_stop, ..Default::default() })
+                            });
+                            *current_toy = toy;
+                            if scheduler.push(output, timing) && let Some(write_stats) = write_stats.lock().expect("Could not lock").get(&current_toy.identifier) {
+                                write_stats.lock().expect("Could not lock").dropped += 1;
+                            }
+                        }
+                        AdvCommand::Remove(identifier) => {
+                            if toys.remove(&identifier).is_some() {
+                                _ = advertiser.stop(&identifier).await;
+                            }
+                        }
+                        AdvCommand::SetIdleStop(new_idle_stop) => {
+                            idle_stop = new_idle_stop;
+                            for (_, scheduler) in toys.values_mut() {
+                                scheduler.idle_stop = idle_stop;
+                            }
+                        }
+                        AdvCommand::SetOptions(new_options) => {
+                            restart |= options != new_options;
+                            options = new_options;
+                        }
+                        AdvCommand::SetLearning(new_learning) => {
+                            learning = new_learning;
+                            let result = match learning {
+                                true => advertiser.scan(DEFAULT_COMPANY_ID, learn_tx.clone()).await,
+                                false => advertiser.stop_scan().await,
+                            };
+                            if let Err(error) = result {
+                                eprintln!("Failed to scan for remotes: {}", error);
+                            }
+                        }
+                        AdvCommand::SelectAdapter(adapter) => {
+                            if let Err(error) = advertiser.init(adapter.as_deref()).await {
+                                eprintln!("{}", error);
+                                continue;
+                            }
+                            if learning && let Err(error) = advertiser.scan(DEFAULT_COMPANY_ID, learn_tx.clone()).await {
+                                eprintln!("Failed to scan for remotes: {}", error);
+                            }
+                            restart = true;
+                        }
+                    }
+                }
 
-                    // println!("Speed set to {}", speed);
+                // Continue advertising the current outputs with the new adapter or options
+                if restart {
+                    for (identifier, (toy, scheduler)) in &toys {
+                        if let Some(output) = scheduler.advertised_output() {
+                            _ = advertiser.send(identifier, toy.company_id, &protocol(&toy.protocol).payload(&toy.address, output), options).await;
+                        }
+                    }
                 }
             }
         });
     }
 
-    pub fn send_speed(&mut self, speed: u8) -> anyhow::Result<()> {
-        if speed == self.last_speed {
-            return Ok(());
-        }
-
+    pub fn create_device(&self, toy: GenericToy) -> anyhow::Result<GenericOutputDevice> {
         if let Some(gui_tx) = &self.gui_tx {
-            gui_tx.send(speed)?;
-            self.last_speed = speed;
+            let write_stats = self.write_stats
+                .lock()
+                .expect("Could not lock")
+                .entry(toy.identifier.clone())
+                .or_default()
+                .clone();
+
+            return Ok(GenericOutputDevice {
+                gui_tx: gui_tx.clone(),
+                toy,
+                pattern: None,
+                last_level: None,
+                last_output: None,
+                write_timing: WriteTiming::default(),
+                write_stats,
+            });
         }
 
         Err(anyhow::anyhow!("Missing message channel!"))
     }
 }
 
-#[allow(unused)]
+// Commands sent from GUI thread to the advertising thread
 #[derive(Debug)]
+pub enum AdvCommand {
+    SetOutput(GenericToy, AdvOutput, WriteTiming), // toy, level or program, pacing of the advertisements
+    Remove(String), // toy identifier, stops its advertisement
+    SelectAdapter(Option<String>), // adapter name, None for the default adapter
+    SetIdleStop(Option<Duration>),
+    SetOptions(AdvOptions),
+    SetLearning(bool), // scans for stock remotes while true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdvAction {
+    Send(AdvOutput),
+    Stop,
+}
+
+/// Decides when the advertising thread has to (re-)send the output or stop advertising
+#[derive(Debug, Default)]
+struct AdvScheduler {
+    pending_output: Option<AdvOutput>,
+    last_output: Option<AdvOutput>,
+    last_send: Option<Instant>,
+    advertising: bool,
+    timing: WriteTiming,
+    idle_stop: Option<Duration>,
+}
+
+impl AdvScheduler {
+    /// Queues a new output, returns whether it replaced one that was not advertised yet
+    fn push(&mut self, output: AdvOutput, timing: WriteTiming) -> bool {
+        self.timing = timing;
+        self.pending_output.replace(output).is_some()
+    }
+
+    /// Records an advertisement, returns whether it was a new output rather than a keepalive
+    fn sent(&mut self, output: AdvOutput, now: Instant) -> bool {
+        self.last_output.replace(output);
+        self.last_send.replace(now);
+        self.advertising = true;
+        self.pending_output.take().is_some()
+    }
+
+    fn stopped(&mut self) {
+        self.advertising = false;
+    }
+
+    fn advertised_output(&self) -> Option<AdvOutput> {
+        self.last_output.filter(|_| self.advertising)
+    }
+
+    /// Next action and when it is due, `None` if nothing happens until the next command
+    fn next_action(&self, now: Instant) -> Option<(AdvAction, Instant)> {
+        if let Some(output) = self.pending_output {
+            let due = self.last_send.map_or(now, |last_send| last_send + self.timing.min_interval);
+            return Some((AdvAction::Send(output), due));
+        }
+
+        let (Some(output), Some(last_send)) = (self.advertised_output(), self.last_send) else {
+            return None;
+        };
+
+        match output.is_off() {
+            true => self.idle_stop.map(|idle_stop| (AdvAction::Stop, last_send + idle_stop)),
+            false => self.timing.keepalive.map(|keepalive| (AdvAction::Send(output), last_send + keepalive)),
+        }
+    }
+}
+
+/// Output handle for an advertisement based generic toy
+pub struct GenericOutputDevice {
+    gui_tx: Sender<AdvCommand>,
+    toy: GenericToy,
+    pattern: Option<usize>,
+    last_level: Option<u8>,
+    last_output: Option<AdvOutput>,
+    write_timing: WriteTiming,
+    write_stats: SharedWriteStats,
+}
+
+impl GenericOutputDevice {
+    /// Sends the program instead of the level while the toy is running
+    fn send_level(&mut self, level: u8) -> anyhow::Result<()> {
+        let output = match self.pattern {
+            Some(pattern) if level > 0 => AdvOutput::Pattern(pattern),
+            _ => AdvOutput::Speed(level),
+        };
+        self.last_level.replace(level);
+        if self.last_output == Some(output) {
+            return Ok(());
+        }
+
+        self.gui_tx.send(AdvCommand::SetOutput(self.toy.clone(), output, self.write_timing))?;
+        self.last_output.replace(output);
+        Ok(())
+    }
+}
+
+impl OutputDevice for GenericOutputDevice {
+    fn name(&self) -> String {
+        self.toy.name.clone()
+    }
+
+    fn identifier(&self) -> String {
+        self.toy.identifier.clone()
+    }
+
+    fn protocol(&self) -> Option<String> {
+        Some(protocol(&self.toy.protocol).id.clone())
+    }
+
+    fn steps(&self) -> u8 {
+        protocol(&self.toy.protocol).steps()
+    }
+
+    /// Stops advertising, the next intensity starts it again
+    fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.gui_tx.send(AdvCommand::Remove(self.toy.identifier.clone()))?;
+        self.last_level.take();
+        self.last_output.take();
+        Ok(())
+    }
+
+    fn set_intensity(&mut self, intensity: f32) -> anyhow::Result<()> {
+        self.send_level(intensity_to_level(intensity, self.steps()))
+    }
+
+    fn patterns(&self) -> Vec<String> {
+        protocol(&self.toy.protocol).patterns.iter().map(|pattern| pattern.name.clone()).collect()
+    }
+
+    fn set_pattern(&mut self, pattern: Option<usize>) -> anyhow::Result<()> {
+        if pattern.is_some_and(|pattern| pattern >= protocol(&self.toy.protocol).patterns.len()) {
+            anyhow::bail!("Unknown pattern");
+        }
+
+        self.pattern = pattern;
+        match self.last_level {
+            Some(level) => self.send_level(level),
+            None => Ok(()),
+        }
+    }
+
+    fn set_write_timing(&mut self, timing: WriteTiming) {
+        self.write_timing = timing;
+    }
+
+    fn min_level_duration(&self) -> Duration {
+        MIN_LEVEL_DURATION
+    }
+
+    fn write_stats(&self) -> Option<WriteStats> {
+        Some(*self.write_stats.lock().expect("Could not lock"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
     Raw([u8; 3]),
     Byte(u8),
 }
 
+/// Seeds of the two whitening passes and the header in front of the address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WhiteningScheme {
+    pub seeds: [u8; 2],
+    pub header: [u8; 3],
+}
+
 pub struct BleUtil;
 
 impl BleUtil {
-    pub fn get_ble_command(address_bytes: &[u8; 5], command_bytes: Command) -> Vec<u8> {
+    pub fn get_ble_command(scheme: &WhiteningScheme, address_bytes: &[u8; 5], command_bytes: Command) -> Vec<u8> {
         let addr_len = address_bytes.len();
         let total_len = addr_len + 1 + 5;
         let mut result = vec![0u8; total_len];
 
         match command_bytes {
             Command::Byte(val) => {
-                Self::get_rf_payload(&address_bytes, &vec![val], &mut result);
+                Self::get_rf_payload(scheme, address_bytes, &[val], &mut result);
 
                 result
             }
             Command::Raw(bytes) => {
-                Self::get_rf_payload(&address_bytes, &vec![0], &mut result);
+                Self::get_rf_payload(scheme, address_bytes, &[0], &mut result);
                 result[8..11].copy_from_slice(&bytes);
 
                 result
@@ -130,12 +546,12 @@ impl BleUtil {
         }
     }
 
-    fn get_rf_payload(addr: &[u8; 5], data: &[u8], result: &mut [u8]) {
+    fn get_rf_payload(scheme: &WhiteningScheme, addr: &[u8; 5], data: &[u8], result: &mut [u8]) {
         let mut ctx_25 = [0u8; 7];
         let mut ctx_3f = [0u8; 7];
 
-        Self::whitening_init(0x25, &mut ctx_25);
-        Self::whitening_init(0x3f, &mut ctx_3f);
+        Self::whitening_init(scheme.seeds[0], &mut ctx_25);
+        Self::whitening_init(scheme.seeds[1], &mut ctx_3f);
 
         let length_24 = 0x12 + addr.len() + data.len();
         let length_26 = length_24 + 0x02;
@@ -145,9 +561,7 @@ impl BleUtil {
         let mut result_buf = vec![0u8; length_26];
 
         // Set constant values
-        result_buf[0x0f] = 0x71;
-        result_buf[0x10] = 0x0f;
-        result_buf[0x11] = 0x55;
+        result_buf[0x0f..0x12].copy_from_slice(&scheme.header);
 
         // Flip and write address
         for j in 0..addr.len() {
@@ -188,7 +602,7 @@ impl BleUtil {
         result[..11].copy_from_slice(&result_25[0x0f..0x1a]);
     }
 
-    fn whitening_init(val: u8, ctx: &mut [u8; 7]) {
+    pub fn whitening_init(val: u8, ctx: &mut [u8; 7]) {
         ctx[0] = 1;
         ctx[1] = (val >> 5) & 1;
         ctx[2] = (val >> 4) & 1;
@@ -198,7 +612,7 @@ impl BleUtil {
         ctx[6] = val & 1;
     }
 
-    fn check_crc16(addr: &[u8], data: &[u8]) -> u16 {
+    pub fn check_crc16(addr: &[u8], data: &[u8]) -> u16 {
         let mut crc: u32 = 0xffff;
 
         // Process address bytes (reversed)
@@ -214,8 +628,8 @@ impl BleUtil {
         }
 
         // Process data bytes
-        for i in 0..data.len() {
-            crc ^= (Self::invert_8(data[i]) as u32) << 8;
+        for byte in data {
+            crc ^= (Self::invert_8(*byte) as u32) << 8;
             for _ in 0..8 {
                 if (crc & 0x8000) != 0 {
                     crc = (crc << 1) ^ 0x1021;
@@ -292,6 +706,105 @@ impl BleUtil {
 }
 
 pub trait BleAdvertiser {
-    async fn init(&mut self) -> anyhow::Result<()>;
-    async fn send(&mut self, mfr_id: u16, data: &[u8]) -> anyhow::Result<()>;
+    async fn init(&mut self, adapter: Option<&str>) -> anyhow::Result<()>;
+    async fn adapters(&self) -> anyhow::Result<Vec<String>>;
+    /// Starts or replaces the advertisement of the toy with the given identifier
+    async fn send(&mut self, identifier: &str, mfr_id: u16, data: &[u8], options: AdvOptions) -> anyhow::Result<()>;
+    async fn stop(&mut self, identifier: &str) -> anyhow::Result<()>;
+    /// Reports the manufacturer data with the given company ID of nearby advertisements until `stop_scan`
+    async fn scan(&mut self, company_id: u16, scan_tx: Sender<(u16, Vec<u8>)>) -> anyhow::Result<()>;
+    async fn stop_scan(&mut self) -> anyhow::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::AdvOutput::Speed;
+
+    #[test]
+    fn schedules_keepalive_and_idle_stop() {
+        let start = Instant::now();
+        let mut scheduler = AdvScheduler {
+            idle_stop: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let timing = WriteTiming {
+            min_interval: Duration::from_millis(100),
+            keepalive: Some(Duration::from_secs(2)),
+        };
+
+        assert!(!scheduler.push(Speed(5), timing));
+        assert_eq!(scheduler.next_action(start), Some((AdvAction::Send(Speed(5)), start)));
+        assert!(scheduler.sent(Speed(5), start));
+
+        // Newer speeds replace pending ones and respect the minimum interval
+        assert!(!scheduler.push(Speed(6), timing));
+        assert!(scheduler.push(Speed(7), timing));
+        assert_eq!(scheduler.next_action(start), Some((AdvAction::Send(Speed(7)), start + timing.min_interval)));
+        assert!(scheduler.sent(Speed(7), start));
+
+        // A steady level is re-sent, but keepalives do not count as new writes
+        assert_eq!(scheduler.next_action(start), Some((AdvAction::Send(Speed(7)), start + Duration::from_secs(2))));
+        assert!(!scheduler.sent(Speed(7), start));
+
+        scheduler.push(Speed(0), timing);
+        assert!(scheduler.sent(Speed(0), start));
+        assert_eq!(scheduler.next_action(start), Some((AdvAction::Stop, start + Duration::from_secs(10))));
+        scheduler.stopped();
+        assert_eq!(scheduler.next_action(start), None);
+    }
+
+    #[test]
+    fn generic_toys_are_told_apart_by_address() {
+        let mut toys = vec![GenericToy::default()];
+        toys.push(GenericToy {
+            identifier: GenericToy::next_identifier(&toys),
+            address: [0x01, 0x02, 0x03, 0x04, 0x05],
+            ..Default::default()
+        });
+        assert_eq!(toys[1].identifier, "generic-2");
+
+        // Removing the first toy frees the plain identifier again
+        toys.remove(0);
+        assert_eq!(GenericToy::next_identifier(&toys), "generic");
+
+        let payload = BluetoothGenericService::speed_to_payload(&toys[0].address, 3);
+        assert_ne!(payload, BluetoothGenericService::speed_to_payload(&DEFAULT_ADDRESS, 3));
+        assert_eq!(&payload[..3], &[0x02, 0x01, 0x06]);
+    }
+
+    #[test]
+    fn plays_patterns_while_running() {
+        let (gui_tx, ble_rx) = channel();
+        let mut device = GenericOutputDevice {
+            gui_tx,
+            toy: GenericToy::default(),
+            pattern: None,
+            last_level: None,
+            last_output: None,
+            write_timing: WriteTiming::default(),
+            write_stats: SharedWriteStats::default(),
+        };
+        let outputs = || ble_rx.try_iter().filter_map(|command| match command {
+            AdvCommand::SetOutput(_, output, _) => Some(output),
+            _ => None,
+        }).collect::<Vec<AdvOutput>>();
+
+        device.set_pattern(Some(2)).unwrap();
+        assert!(outputs().is_empty());
+
+        // Any level above 0 plays the program, level 0 stops it
+        device.set_intensity(0.5).unwrap();
+        device.set_intensity(0.8).unwrap();
+        device.set_intensity(0.0).unwrap();
+        assert_eq!(outputs(), vec![AdvOutput::Pattern(2), Speed(0)]);
+
+        device.set_intensity(0.5).unwrap();
+        device.set_pattern(None).unwrap();
+        assert_eq!(outputs(), vec![AdvOutput::Pattern(2), Speed(3)]);
+        assert!(device.set_pattern(Some(device.patterns().len())).is_err());
+
+        let pattern = protocol(CLASSIC_PROTOCOL).payload(&DEFAULT_ADDRESS, AdvOutput::Pattern(0));
+        assert_eq!(&pattern[11..], &[0xE4, 0x9C, 0x6C]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use crate::bluetooth::adv_decoder::{decode, DecodedPayload};
+use crate::bluetooth::btsnoop;
+use crate::bluetooth::generic::{AdvOutput, DEFAULT_COMPANY_ID};
+
+/// Addresses of stock remotes, collected from their advertisements to pair generic toys with them
+#[derive(Debug, Default)]
+pub struct AdvLearner {
+    remotes: BTreeMap<[u8; 5], LearnedRemote>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LearnedRemote {
+    pub address: [u8; 5],
+    pub company_id: u16,
+    pub protocol: String, // ADV protocol identifier
+    pub raw_command: [u8; 3], // last command seen
+    pub output: Option<AdvOutput>,
+    pub count: u32,
+}
+
+impl AdvLearner {
+    /// Records a payload of any known ADV protocol, returns whether it was one
+    pub fn manufacturer_data(&mut self, company_id: u16, data: &[u8]) -> bool {
+        // Other devices may use the same company ID, the header tells protocol payloads apart
+        if company_id != DEFAULT_COMPANY_ID {
+            return false;
+        }
+        let Some(decoded) = decode(data).ok().filter(|decoded| decoded.header_valid()) else {
+            return false;
+        };
+
+        let DecodedPayload { protocol, address, raw_command, output, .. } = decoded;
+        let protocol = protocol.unwrap_or_default();
+        let remote = self.remotes.entry(address).or_insert(LearnedRemote {
+            address,
+            company_id,
+            protocol: protocol.clone(),
+            raw_command,
+            output: None,
+            count: 0,
+        });
+        remote.company_id = company_id;
+        remote.protocol = protocol;
+        remote.raw_command = raw_command;
+        remote.output = output;
+        remote.count += 1;
+        true
+    }
+
+    /// Reads the advertisements of a btsnoop HCI log, returns how many belonged to the protocol
+    pub fn import_btsnoop(&mut self, path: &Path) -> anyhow::Result<usize> {
+        let log = std::fs::read(path)?;
+        let imported = btsnoop::manufacturer_data(&log)?
+            .into_iter()
+            .filter(|(company_id, data)| self.manufacturer_data(*company_id, data))
+            .count();
+        Ok(imported)
+    }
+
+    pub fn remotes(&self) -> Vec<&LearnedRemote> {
+        self.remotes.values().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.remotes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bluetooth::adv_protocol::CLASSIC_PROTOCOL;
+    use crate::bluetooth::generic::BluetoothGenericService;
+    use super::*;
+
+    #[test]
+    fn learns_remote_addresses() {
+        let address = [0x10, 0x20, 0x30, 0x40, 0x50];
+        let mut learner = AdvLearner::default();
+
+        let payload = BluetoothGenericService::speed_to_payload(&address, 2);
+        assert!(learner.manufacturer_data(DEFAULT_COMPANY_ID, &payload));
+        assert!(learner.manufacturer_data(DEFAULT_COMPANY_ID, &payload));
+
+        // Payloads under other company IDs and unrelated manufacturer data are ignored
+        assert!(!learner.manufacturer_data(0x004C, &payload));
+        assert!(!learner.manufacturer_data(DEFAULT_COMPANY_ID, &[0x02, 0x15, 0x00]));
+        assert!(!learner.manufacturer_data(DEFAULT_COMPANY_ID, &[0u8; 11]));
+
+        let remotes = learner.remotes();
+        assert_eq!(remotes.len(), 1);
+        assert_eq!(remotes[0].address, address);
+        assert_eq!(remotes[0].company_id, DEFAULT_COMPANY_ID);
+        assert_eq!(remotes[0].protocol, CLASSIC_PROTOCOL);
+        assert_eq!(remotes[0].output, Some(AdvOutput::Speed(2)));
+        assert_eq!(remotes[0].count, 2);
+    }
+}
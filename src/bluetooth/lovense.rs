@@ -0,0 +1,216 @@
+// https://docs.buttplug.io/docs/stpihkal/protocols/lovense
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LovenseCommand {
+    Vibrate(u8),     // 0-20
+    VibrateMotor(u8, u8), // motor (1-based), 0-20
+    Rotate(u8),      // 0-20
+    RotateChange,    // reverses the rotation direction
+    AirLevel(u8),    // 0-5, absolute air pump level
+    Pump(u8),        // 0-3, air pump strength
+    Preset(u8),      // built-in pattern, 0 stops the pattern
+    PowerOff,
+    Battery,
+    DeviceType,
+}
+
+impl LovenseCommand {
+    pub const MAX_VIBRATE: u8 = 20;
+    pub const MAX_ROTATE: u8 = 20;
+    pub const MAX_AIR_LEVEL: u8 = 5;
+    pub const MAX_PUMP: u8 = 3;
+    pub const MAX_PRESET: u8 = 10;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let command = match self {
+            LovenseCommand::Vibrate(level) => format!("Vibrate:{};", (*level).min(Self::MAX_VIBRATE)),
+            LovenseCommand::VibrateMotor(motor, level) => format!("Vibrate{}:{};", motor, (*level).min(Self::MAX_VIBRATE)),
+            LovenseCommand::Rotate(level) => format!("Rotate:{};", (*level).min(Self::MAX_ROTATE)),
+            LovenseCommand::RotateChange => "RotateChange;".into(),
+            LovenseCommand::AirLevel(level) => format!("Air:Level:{};", (*level).min(Self::MAX_AIR_LEVEL)),
+            LovenseCommand::Pump(level) => format!("Pump:{};", (*level).min(Self::MAX_PUMP)),
+            LovenseCommand::Preset(preset) => format!("Preset:{};", (*preset).min(Self::MAX_PRESET)),
+            LovenseCommand::PowerOff => "PowerOff;".into(),
+            LovenseCommand::Battery => "Battery;".into(),
+            LovenseCommand::DeviceType => "DeviceType;".into(),
+        };
+
+        command.into_bytes()
+    }
+
+    /// Parses a command the way a toy would receive it, e.g. `Vibrate:10;`
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let command = std::str::from_utf8(data).ok()?.trim().strip_suffix(';')?;
+        let parts = command.split(':').collect::<Vec<&str>>();
+
+        let command = match parts.as_slice() {
+            ["Vibrate", level] => LovenseCommand::Vibrate(level.parse().ok()?),
+            [name, level] if name.starts_with("Vibrate") => {
+                LovenseCommand::VibrateMotor(name["Vibrate".len()..].parse().ok()?, level.parse().ok()?)
+            }
+            ["Rotate", level] => LovenseCommand::Rotate(level.parse().ok()?),
+            ["RotateChange"] => LovenseCommand::RotateChange,
+            ["Air", "Level", level] => LovenseCommand::AirLevel(level.parse().ok()?),
+            ["Pump", level] => LovenseCommand::Pump(level.parse().ok()?),
+            ["Preset", preset] => LovenseCommand::Preset(preset.parse().ok()?),
+            ["PowerOff"] => LovenseCommand::PowerOff,
+            ["Battery"] => LovenseCommand::Battery,
+            ["DeviceType"] => LovenseCommand::DeviceType,
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LovenseReply {
+    Ok,
+    Error,
+    Battery(u8),
+    DeviceType(LovenseDeviceType),
+    Unknown(String),
+}
+
+impl LovenseReply {
+    pub fn parse(data: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(data);
+        let reply = text.trim().trim_end_matches(';');
+
+        match reply {
+            "OK" => LovenseReply::Ok,
+            "ERR" => LovenseReply::Error,
+            _ if let Some(device_type) = LovenseDeviceType::parse(reply) => LovenseReply::DeviceType(device_type),
+            // Some firmwares prefix the battery level with 's' while a motor is running
+            _ => match reply.trim_start_matches('s').parse::<u8>() {
+                Ok(level) if level <= 100 => LovenseReply::Battery(level),
+                _ => LovenseReply::Unknown(reply.into()),
+            },
+        }
+    }
+}
+
+/// Reply to `DeviceType;`, e.g. `C:11:0082059AD3BD;`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LovenseDeviceType {
+    pub model: String,
+    pub firmware: u32,
+    pub address: String,
+}
+
+impl LovenseDeviceType {
+    fn parse(reply: &str) -> Option<Self> {
+        let [model, firmware, address] = reply.split(':').collect::<Vec<&str>>()[..] else {
+            return None;
+        };
+
+        if model.is_empty() || !model.chars().all(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+
+        Some(Self {
+            model: model.into(),
+            firmware: firmware.parse().ok()?,
+            address: address.into(),
+        })
+    }
+
+    fn known_model(&self) -> Option<&'static LovenseModel> {
+        LOVENSE_MODELS.iter().find(|model| model.code == self.model)
+    }
+
+    pub fn model_name(&self) -> String {
+        self.known_model()
+            .map_or(format!("Unknown ({})", self.model), |model| model.name.into())
+    }
+
+    /// Capabilities of the detected model, `None` if the model is not known
+    pub fn capabilities(&self) -> Option<LovenseCapabilities> {
+        self.known_model().map(|model| model.capabilities)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LovenseCapabilities {
+    pub motors: u8,
+    pub rotation: bool,
+    pub air_pump: bool,
+}
+
+struct LovenseModel {
+    code: &'static str,
+    name: &'static str,
+    capabilities: LovenseCapabilities,
+}
+
+const fn model(code: &'static str, name: &'static str, motors: u8, rotation: bool, air_pump: bool) -> LovenseModel {
+    LovenseModel {
+        code,
+        name,
+        capabilities: LovenseCapabilities { motors, rotation, air_pump },
+    }
+}
+
+const LOVENSE_MODELS: &[LovenseModel] = &[
+    model("A", "Nora", 1, true, false),
+    model("C", "Nora", 1, true, false),
+    model("B", "Max", 1, false, true),
+    model("S", "Lush", 1, false, false),
+    model("Z", "Hush", 1, false, false),
+    model("W", "Domi", 1, false, false),
+    model("P", "Edge", 2, false, false),
+    model("J", "Dolce", 2, false, false),
+    model("L", "Ambi", 1, false, false),
+    model("O", "Osci", 1, false, false),
+    model("R", "Diamo", 1, false, false),
+    model("F", "Ferri", 1, false, false),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            LovenseCommand::Vibrate(12),
+            LovenseCommand::VibrateMotor(2, 7),
+            LovenseCommand::Rotate(3),
+            LovenseCommand::RotateChange,
+            LovenseCommand::AirLevel(4),
+            LovenseCommand::Pump(2),
+            LovenseCommand::Preset(1),
+            LovenseCommand::PowerOff,
+            LovenseCommand::Battery,
+            LovenseCommand::DeviceType,
+        ];
+
+        for command in commands {
+            assert_eq!(LovenseCommand::parse(&command.encode()), Some(command));
+        }
+        assert_eq!(LovenseCommand::AirLevel(9).encode(), b"Air:Level:5;");
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(LovenseReply::parse(b"OK;"), LovenseReply::Ok);
+        assert_eq!(LovenseReply::parse(b"ERR;"), LovenseReply::Error);
+        assert_eq!(LovenseReply::parse(b"85;"), LovenseReply::Battery(85));
+        assert_eq!(LovenseReply::parse(b"s42;"), LovenseReply::Battery(42));
+        assert_eq!(LovenseReply::parse(b"what;"), LovenseReply::Unknown("what".into()));
+    }
+
+    #[test]
+    fn parses_device_type() {
+        let LovenseReply::DeviceType(device_type) = LovenseReply::parse(b"C:11:0082059AD3BD;") else {
+            panic!("Expected a device type reply");
+        };
+
+        assert_eq!(device_type.model, "C");
+        assert_eq!(device_type.firmware, 11);
+        assert_eq!(device_type.address, "0082059AD3BD");
+        assert_eq!(device_type.model_name(), "Nora");
+        assert!(device_type.capabilities().unwrap().rotation);
+    }
+}
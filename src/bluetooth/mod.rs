@@ -1,4 +1,16 @@
+pub mod adv_decoder;
+pub mod adv_protocol;
+pub mod btsnoop;
+pub mod central;
+pub mod device_config;
+pub mod diagnostics;
+#[cfg(test)]
+pub mod fake;
 pub mod gatt;
 pub mod generic;
+pub mod learn;
+pub mod lovense;
+pub mod protocol;
+pub mod registry;
 mod adv_linux;
 mod adv_windows;
\ No newline at end of file
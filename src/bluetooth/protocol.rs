@@ -0,0 +1,79 @@
+use uuid::Uuid;
+use crate::bluetooth::lovense::LovenseCommand;
+
+/// Protocol families with a built-in encoder, ordered by matching priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolFamily {
+    Lovense,
+    WeVibe,
+    KiirooV21,
+}
+
+impl ProtocolFamily {
+    /// Maps a protocol name of the buttplug device config onto a known family
+    pub fn from_protocol_name(name: &str) -> Option<Self> {
+        match name {
+            "lovense" => Some(ProtocolFamily::Lovense),
+            "wevibe" => Some(ProtocolFamily::WeVibe),
+            "kiiroo-v21" => Some(ProtocolFamily::KiirooV21),
+            _ => None,
+        }
+    }
+
+    /// Whether a service UUID belongs to the family even if the device config does not list it.
+    /// Lovense toys use `xxxx0001-00xx-4bd4-bbd5-a6920e4c5653` services, e.g. `5a300001-0024-…`.
+    pub fn matches_service(&self, service: &Uuid) -> bool {
+        match self {
+            ProtocolFamily::Lovense => {
+                let service = service.to_string();
+                &service[4..8] == "0001" && service.ends_with("-4bd4-bbd5-a6920e4c5653")
+            }
+            _ => false,
+        }
+    }
+
+    /// Highest vibration level the family accepts
+    pub fn steps(&self) -> u8 {
+        match self {
+            ProtocolFamily::Lovense => LovenseCommand::MAX_VIBRATE,
+            ProtocolFamily::WeVibe => 12,
+            ProtocolFamily::KiirooV21 => 100,
+        }
+    }
+
+    /// Encodes a vibration command that drives every motor of the toy
+    pub fn encode_vibrate(&self, level: u8) -> Vec<u8> {
+        let level = level.min(self.steps());
+        match self {
+            ProtocolFamily::Lovense => LovenseCommand::Vibrate(level).encode(),
+            ProtocolFamily::WeVibe if level == 0 => vec![0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            // Internal motor in the high nibble, external in the low nibble
+            ProtocolFamily::WeVibe => vec![0x0f, 0x03, 0x00, level | (level << 4), 0x00, 0x03, 0x00, 0x00],
+            ProtocolFamily::KiirooV21 => vec![0x01, level],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_vibrate_commands() {
+        assert_eq!(ProtocolFamily::Lovense.encode_vibrate(25), b"Vibrate:20;");
+        assert_eq!(ProtocolFamily::WeVibe.encode_vibrate(5), vec![0x0f, 0x03, 0x00, 0x55, 0x00, 0x03, 0x00, 0x00]);
+        assert_eq!(ProtocolFamily::WeVibe.encode_vibrate(0), vec![0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(ProtocolFamily::KiirooV21.encode_vibrate(42), vec![0x01, 42]);
+    }
+
+    #[test]
+    fn matches_lovense_service_family() {
+        for service in ["455a0001-0023-4bd4-bbd5-a6920e4c5653", "5a300001-0024-4bd4-bbd5-a6920e4c5653", "57300001-0023-4bd4-bbd5-a6920e4c5653"] {
+            assert!(ProtocolFamily::Lovense.matches_service(&Uuid::parse_str(service).unwrap()));
+        }
+
+        let tx = Uuid::parse_str("5a300002-0024-4bd4-bbd5-a6920e4c5653").unwrap();
+        assert!(!ProtocolFamily::Lovense.matches_service(&tx));
+        assert!(!ProtocolFamily::WeVibe.matches_service(&Uuid::from_u128(0x455a0001_0023_4bd4_bbd5_a6920e4c5653)));
+    }
+}
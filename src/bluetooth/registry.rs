@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Signal strength and last advertisement of every discovered GATT device, keyed by address
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<String, Presence>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Presence {
+    pub rssi: Option<i16>,
+    pub last_seen: Instant,
+}
+
+impl DeviceRegistry {
+    /// Records an advertisement of a device, returns whether it was not known yet
+    pub fn seen(&mut self, address: &str, rssi: Option<i16>, now: Instant) -> bool {
+        match self.devices.get_mut(address) {
+            Some(presence) => {
+                presence.rssi = rssi.or(presence.rssi);
+                presence.last_seen = now;
+                false
+            }
+            None => {
+                self.devices.insert(address.into(), Presence { rssi, last_seen: now });
+                true
+            }
+        }
+    }
+
+    /// Keeps a device present without an advertisement, connected toys usually stop advertising
+    pub fn touch(&mut self, address: &str, now: Instant) {
+        if let Some(presence) = self.devices.get_mut(address) {
+            presence.last_seen = now;
+        }
+    }
+
+    pub fn presence(&self, address: &str) -> Option<Presence> {
+        self.devices.get(address).copied()
+    }
+
+    /// Forgets devices that were not seen for longer than the timeout, returns their addresses
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<String> {
+        let expired = self.devices
+            .iter()
+            .filter(|(_, presence)| now.saturating_duration_since(presence.last_seen) > timeout)
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<String>>();
+
+        for address in &expired {
+            self.devices.remove(address);
+        }
+
+        expired
+    }
+
+    pub fn clear(&mut self) {
+        self.devices.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_and_expires_devices() {
+        let start = Instant::now();
+        let mut registry = DeviceRegistry::default();
+
+        assert!(registry.seen("AA:BB", Some(-60), start));
+        assert!(!registry.seen("AA:BB", None, start + Duration::from_secs(5)));
+        assert!(registry.seen("CC:DD", Some(-80), start));
+
+        // Updates without a signal strength keep the last known one
+        let presence = registry.presence("AA:BB").unwrap();
+        assert_eq!(presence.rssi, Some(-60));
+        assert_eq!(presence.last_seen, start + Duration::from_secs(5));
+
+        registry.touch("CC:DD", start + Duration::from_secs(20));
+        assert_eq!(registry.expire(start + Duration::from_secs(30), Duration::from_secs(20)), vec!["AA:BB".to_string()]);
+        assert!(registry.presence("AA:BB").is_none());
+        assert!(registry.presence("CC:DD").is_some());
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use crate::device::intensity_to_level;
+
+/// Perceptible level range of a device, intensities are mapped into it instead of the full range
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    pub min_level: u8, // lowest level that can be felt
+    pub max_level: u8, // strongest comfortable level
+}
+
+impl Calibration {
+    /// Maps a normalized intensity onto the calibrated range, intensities below level 1 stay off
+    pub fn apply(&self, intensity: f32, steps: u8) -> f32 {
+        if steps == 0 || intensity_to_level(intensity, steps) == 0 {
+            return 0.0;
+        }
+
+        let min_level = self.min_level.min(steps) as f32;
+        let max_level = self.max_level.clamp(self.min_level, steps) as f32;
+        (min_level + intensity.clamp(0.0, 1.0) * (max_level - min_level)) / steps as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationStage {
+    Minimum,
+    Maximum { min_level: u8 },
+    Done(Calibration),
+}
+
+/// Steps a single device through its levels to find the minimum felt and maximum comfortable one
+#[derive(Debug)]
+pub struct CalibrationWizard {
+    identifier: String,
+    steps: u8,
+    level: u8,
+    stage: CalibrationStage,
+}
+
+impl CalibrationWizard {
+    pub fn new(identifier: String, steps: u8) -> Self {
+        Self {
+            identifier,
+            steps,
+            level: 1.min(steps),
+            stage: CalibrationStage::Minimum,
+        }
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    pub fn steps(&self) -> u8 {
+        self.steps
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn stage(&self) -> CalibrationStage {
+        self.stage
+    }
+
+    /// Intensity the device is driven with while calibrating, off once the wizard is done
+    pub fn intensity(&self) -> f32 {
+        match self.stage {
+            CalibrationStage::Done(_) => 0.0,
+            _ if self.steps == 0 => 0.0,
+            _ => self.level as f32 / self.steps as f32,
+        }
+    }
+
+    pub fn step_up(&mut self) {
+        self.level = (self.level + 1).min(self.steps);
+    }
+
+    /// Lowers the level, the maximum cannot go below the recorded minimum
+    pub fn step_down(&mut self) {
+        let lowest = match self.stage {
+            CalibrationStage::Maximum { min_level } => min_level,
+            _ => 1.min(self.steps),
+        };
+        self.level = self.level.saturating_sub(1).max(lowest);
+    }
+
+    /// Records the current level for the current stage and advances to the next one
+    pub fn confirm(&mut self) {
+        self.stage = match self.stage {
+            CalibrationStage::Minimum => CalibrationStage::Maximum { min_level: self.level },
+            CalibrationStage::Maximum { min_level } => CalibrationStage::Done(Calibration {
+                min_level,
+                max_level: self.level,
+            }),
+            CalibrationStage::Done(calibration) => CalibrationStage::Done(calibration),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_intensity_into_calibrated_range() {
+        let calibration = Calibration { min_level: 4, max_level: 16 };
+
+        assert_eq!(calibration.apply(0.0, 20), 0.0);
+        assert_eq!(calibration.apply(0.01, 20), 0.0);
+        assert_eq!(intensity_to_level(calibration.apply(0.05, 20), 20), 4);
+        assert_eq!(intensity_to_level(calibration.apply(0.5, 20), 20), 10);
+        assert_eq!(intensity_to_level(calibration.apply(1.0, 20), 20), 16);
+    }
+
+    #[test]
+    fn records_minimum_and_maximum() {
+        let mut wizard = CalibrationWizard::new("AA:BB".into(), 7);
+        wizard.step_up();
+        wizard.step_up();
+        wizard.confirm();
+        assert_eq!(wizard.stage(), CalibrationStage::Maximum { min_level: 3 });
+
+        // The maximum cannot be below the minimum
+        wizard.step_down();
+        assert_eq!(wizard.level(), 3);
+
+        for _ in 0..10 {
+            wizard.step_up();
+        }
+        wizard.step_down();
+        wizard.confirm();
+        assert_eq!(wizard.stage(), CalibrationStage::Done(Calibration { min_level: 3, max_level: 6 }));
+        assert_eq!(wizard.intensity(), 0.0);
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::bluetooth::lovense::LovenseCommand;
+
+pub mod virtual_device;
+
+/// A single controllable output, e.g. a connected GATT toy or an advertisement based toy.
+///
+/// Intensities are always normalized to `0.0..=1.0`, each implementation maps them
+/// onto its own discrete level range (see [`OutputDevice::steps`]).
+pub trait OutputDevice {
+    /// Name shown in the device selector
+    fn name(&self) -> String;
+
+    /// Stable identifier of the device ("generic", "generic-N", "virtual-*", or the BLE MAC of a GATT device)
+    fn identifier(&self) -> String;
+
+    /// BLE address that should be remembered for automatic reconnection, if any
+    fn ble_address(&self) -> Option<String> {
+        None
+    }
+
+    /// Highest discrete level the device accepts
+    fn steps(&self) -> u8;
+
+    /// Bluetooth adapter used by the next connection, `None` picks the default adapter
+    fn set_adapter(&mut self, _adapter: Option<String>) {}
+
+    fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Forgets the last written levels, so the next update is sent even if it did not change
+    fn reset_levels(&mut self) {}
+
+    fn set_intensity(&mut self, intensity: f32) -> anyhow::Result<()>;
+
+    /// Number of independently controllable motors
+    fn motors(&self) -> u8 {
+        1
+    }
+
+    /// Sets the intensity of a single motor (1-based), devices with one motor ignore the index
+    fn set_motor_intensity(&mut self, _motor: u8, intensity: f32) -> anyhow::Result<()> {
+        self.set_intensity(intensity)
+    }
+
+    /// Names of the built-in programs the device can play instead of plain levels
+    fn patterns(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Plays a built-in program whenever the intensity is above 0, `None` goes back to plain levels
+    fn set_pattern(&mut self, _pattern: Option<usize>) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Device has no built-in patterns"))
+    }
+
+    /// Protocol name that keepalive settings are stored under, e.g. `lovense` or the ADV protocol `classic`
+    fn protocol(&self) -> Option<String> {
+        None
+    }
+
+    /// Pacing of the intensity writes of devices with a coalescing backend
+    fn set_write_timing(&mut self, _timing: WriteTiming) {}
+
+    /// Shortest time a level should be held before changing it again, for devices where every change is costly
+    fn min_level_duration(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Write counters of devices whose backend coalesces intensity updates
+    fn write_stats(&self) -> Option<WriteStats> {
+        None
+    }
+
+    /// Whether the device understands the full Lovense command set
+    fn supports_lovense_commands(&self) -> bool {
+        false
+    }
+
+    fn send_lovense_command(&mut self, _command: LovenseCommand) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Device does not support Lovense commands"))
+    }
+}
+
+/// How a backend paces the intensity writes of a device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteTiming {
+    /// Minimum time between two writes, newer values replace the pending one in the meantime
+    pub min_interval: Duration,
+    /// Re-sends the current level after this long without a write, for toys that time out
+    pub keepalive: Option<Duration>,
+}
+
+/// Intensity writes performed by a backend, the ones that were superseded by a newer
+/// value or discarded while the device was not connected, and the ones the backend rejected
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WriteStats {
+    pub written: u32,
+    pub dropped: u32,
+    pub failed: u32,
+}
+
+pub type SharedWriteStats = Arc<Mutex<WriteStats>>;
+
+/// Maps a normalized intensity onto the `0..=steps` level range of a device, truncating like the original sliders.
+///
+/// The small bias keeps intensities computed as `level / steps` on their level despite f32 rounding.
+pub fn intensity_to_level(intensity: f32, steps: u8) -> u8 {
+    (intensity.clamp(0.0, 1.0) * steps as f32 + 1e-4) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_intensity_to_levels() {
+        assert_eq!(intensity_to_level(0.49, 20), 9);
+        assert_eq!(intensity_to_level(0.5, 7), 3);
+        assert_eq!(intensity_to_level(1.5, 7), 7);
+        assert!((0..=37).all(|level| intensity_to_level(level as f32 / 37.0, 37) == level));
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::bluetooth::generic::{BluetoothGenericService, DEFAULT_ADDRESS};
+use crate::bluetooth::lovense::LovenseCommand;
+use crate::device::{intensity_to_level, OutputDevice};
+
+const MAX_LOG_ENTRIES: usize = 200;
+
+pub type CommandLog = Arc<Mutex<VecDeque<RecordedCommand>>>;
+
+/// A device that exists only in memory. It receives the exact same payloads
+/// a real toy of the emulated protocol would, and records them in a shared log.
+pub struct VirtualDevice {
+    protocol: VirtualProtocol,
+    log: CommandLog,
+    last_level: Option<u8>,
+    last_motor_levels: [Option<u8>; 2],
+}
+
+impl VirtualDevice {
+    pub fn new(protocol: VirtualProtocol, log: CommandLog) -> Self {
+        Self {
+            protocol,
+            log,
+            last_level: None,
+            last_motor_levels: [None; 2],
+        }
+    }
+
+    pub fn new_log() -> CommandLog {
+        Arc::new(Mutex::new(VecDeque::new()))
+    }
+
+    /// Decodes a raw payload the way the emulated toy would and records it
+    pub fn receive(&mut self, payload: &[u8]) -> Option<u8> {
+        let level = match self.protocol {
+            VirtualProtocol::Lovense => match LovenseCommand::parse(payload) {
+                Some(LovenseCommand::Vibrate(level) | LovenseCommand::VibrateMotor(_, level)) => Some(level),
+                _ => None,
+            },
+            VirtualProtocol::Generic => (0..=7u8)
+                .find(|level| BluetoothGenericService::speed_to_payload(&DEFAULT_ADDRESS, *level) == payload),
+        };
+
+        let mut log = self.log.lock().expect("Could not lock");
+        if log.len() >= MAX_LOG_ENTRIES {
+            log.pop_front();
+        }
+        log.push_back(RecordedCommand {
+            timestamp: Instant::now(),
+            protocol: self.protocol,
+            payload: payload.to_vec(),
+            level,
+        });
+
+        level
+    }
+}
+
+impl OutputDevice for VirtualDevice {
+    fn name(&self) -> String {
+        match self.protocol {
+            VirtualProtocol::Lovense => "Virtual Device (Lovense)".into(),
+            VirtualProtocol::Generic => "Virtual Device (Generic)".into(),
+        }
+    }
+
+    fn identifier(&self) -> String {
+        match self.protocol {
+            VirtualProtocol::Lovense => "virtual-lovense".into(),
+            VirtualProtocol::Generic => "virtual-generic".into(),
+        }
+    }
+
+    fn steps(&self) -> u8 {
+        match self.protocol {
+            VirtualProtocol::Lovense => LovenseCommand::MAX_VIBRATE,
+            VirtualProtocol::Generic => 7,
+        }
+    }
+
+    fn set_intensity(&mut self, intensity: f32) -> anyhow::Result<()> {
+        let level = intensity_to_level(intensity, self.steps());
+        if self.last_level == Some(level) {
+            return Ok(());
+        }
+
+        self.last_level.replace(level);
+        self.last_motor_levels = [None; 2];
+        let payload = match self.protocol {
+            VirtualProtocol::Lovense => LovenseCommand::Vibrate(level).encode(),
+            VirtualProtocol::Generic => BluetoothGenericService::speed_to_payload(&DEFAULT_ADDRESS, level),
+        };
+
+        self.receive(&payload)
+            .map(|_| ())
+            .ok_or_else(|| anyhow::anyhow!("Virtual device could not decode payload"))
+    }
+
+    fn motors(&self) -> u8 {
+        match self.protocol {
+            VirtualProtocol::Lovense => 2,
+            VirtualProtocol::Generic => 1,
+        }
+    }
+
+    fn set_motor_intensity(&mut self, motor: u8, intensity: f32) -> anyhow::Result<()> {
+        if self.protocol != VirtualProtocol::Lovense {
+            return self.set_intensity(intensity);
+        }
+
+        let level = intensity_to_level(intensity, self.steps());
+        let motor_index = motor.saturating_sub(1) as usize;
+        if self.last_motor_levels.get(motor_index) == Some(&Some(level)) {
+            return Ok(());
+        }
+
+        if let Some(last_level) = self.last_motor_levels.get_mut(motor_index) {
+            last_level.replace(level);
+        }
+        self.last_level.take();
+        self.receive(&LovenseCommand::VibrateMotor(motor, level).encode());
+        Ok(())
+    }
+
+    fn supports_lovense_commands(&self) -> bool {
+        self.protocol == VirtualProtocol::Lovense
+    }
+
+    fn send_lovense_command(&mut self, command: LovenseCommand) -> anyhow::Result<()> {
+        if self.protocol != VirtualProtocol::Lovense {
+            return Err(anyhow::anyhow!("Device does not support Lovense commands"));
+        }
+
+        if let LovenseCommand::Vibrate(level) = command {
+            self.last_level.replace(level);
+        }
+
+        self.receive(&command.encode());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtualProtocol {
+    Lovense,
+    Generic,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedCommand {
+    pub timestamp: Instant,
+    pub protocol: VirtualProtocol,
+    pub payload: Vec<u8>,
+    pub level: Option<u8>,
+}
+
+impl RecordedCommand {
+    pub fn payload_text(&self) -> String {
+        match self.protocol {
+            VirtualProtocol::Lovense => String::from_utf8_lossy(&self.payload).into(),
+            VirtualProtocol::Generic => hex::encode(&self.payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_lovense_commands() {
+        let log = VirtualDevice::new_log();
+        let mut device = VirtualDevice::new(VirtualProtocol::Lovense, log.clone());
+
+        device.set_intensity(0.5).unwrap();
+        device.set_intensity(0.5).unwrap();
+        device.set_intensity(1.0).unwrap();
+
+        let log = log.lock().unwrap();
+        let levels = log.iter().map(|command| command.level).collect::<Vec<_>>();
+        assert_eq!(levels, vec![Some(10), Some(20)]);
+        assert_eq!(log[0].payload_text(), "Vibrate:10;");
+        assert!(log[0].timestamp <= log[1].timestamp);
+    }
+
+    #[test]
+    fn decodes_generic_advertisements() {
+        let log = VirtualDevice::new_log();
+        let mut device = VirtualDevice::new(VirtualProtocol::Generic, log.clone());
+
+        for level in 0..=7 {
+            device.set_intensity(level as f32 / 7.0).unwrap();
+        }
+
+        let log = log.lock().unwrap();
+        let levels = log.iter().filter_map(|command| command.level).collect::<Vec<_>>();
+        assert_eq!(levels, (0..=7).collect::<Vec<_>>());
+    }
+}
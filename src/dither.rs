@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+/// Interval suggested when dithering gets enabled for a device
+pub const DEFAULT_DITHER_INTERVAL_MS: u16 = 200;
+
+/// Sigma-delta modulator that approximates intensities between two levels by alternating them over time
+#[derive(Debug, Default)]
+pub struct Dither {
+    error: f32, // accumulated difference between the requested and the output levels
+    level: Option<u8>,
+    last_step: Option<Instant>,
+}
+
+impl Dither {
+    /// Level to output at `now`, it changes at most once per interval unless the intensity moves by a whole level
+    pub fn level(&mut self, intensity: f32, steps: u8, interval: Duration, now: Instant) -> u8 {
+        let target = intensity.clamp(0.0, 1.0) * steps as f32;
+        if target <= 0.0 {
+            self.error = 0.0;
+            self.level.replace(0);
+            self.last_step.replace(now);
+            return 0;
+        }
+
+        if let (Some(level), Some(last_step)) = (self.level, self.last_step)
+            && now.saturating_duration_since(last_step) < interval
+            && (target - level as f32).abs() < 1.0
+        {
+            return level;
+        }
+
+        let wanted = target + self.error;
+        let level = wanted.round().clamp(0.0, steps as f32);
+        self.error = (wanted - level).clamp(-1.0, 1.0);
+        self.level.replace(level as u8);
+        self.last_step.replace(now);
+        level as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alternates_between_adjacent_levels() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let mut dither = Dither::default();
+
+        let levels = (0..100)
+            .map(|tick| dither.level(0.43, 7, interval, start + interval * tick))
+            .collect::<Vec<u8>>();
+        assert!(levels.iter().all(|&level| level == 3 || level == 4));
+
+        let average = levels.iter().map(|&level| level as f32).sum::<f32>() / levels.len() as f32;
+        assert!((average - 0.43 * 7.0).abs() < 0.05, "average {}", average);
+    }
+
+    #[test]
+    fn holds_levels_for_the_interval() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let mut dither = Dither::default();
+
+        let level = dither.level(0.5, 7, interval, start);
+        assert_eq!(dither.level(0.5, 7, interval, start + Duration::from_millis(50)), level);
+        assert_ne!(dither.level(0.5, 7, interval, start + interval), level);
+
+        // Large changes and stopping are not delayed
+        assert_eq!(dither.level(1.0, 7, interval, start + interval), 7);
+        assert_eq!(dither.level(0.0, 7, interval, start + interval), 0);
+    }
+}
@@ -3,20 +3,29 @@
 use crate::app_context::AppContext;
 
 mod app_context;
-mod consts;
+mod calibration;
+mod dither;
 mod osc_server;
+mod routing;
 mod speed_filter;
 mod settings;
 mod bluetooth;
+mod device;
 mod remote;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Command line tools, without arguments the GUI starts
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.first().is_some_and(|command| command == "decode-adv") {
+        return bluetooth::adv_decoder::run_cli(&args[1..]);
+    }
+
     let window_size = [300.0, 400.0];
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size(&window_size)
-            .with_max_inner_size(&window_size)
+            .with_inner_size(window_size)
+            .with_max_inner_size(window_size)
             .with_min_inner_size(window_size),
         ..Default::default()
     };
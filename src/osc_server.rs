@@ -11,7 +11,7 @@ use wildmatch::WildMatch;
 #[allow(unused)]
 pub struct OscServer {
     pub data_rx: Receiver<OscFloatData>,
-    pub pattern_tx: TokioSender<WildMatch>,
+    pub pattern_tx: TokioSender<Vec<WildMatch>>,
 
     port_update_counter: Arc<AtomicUsize>,
     server_port: Arc<AtomicU16>,
@@ -22,7 +22,7 @@ pub struct OscServer {
 impl OscServer {
     pub fn new(port: u16) -> Self {
         let (data_tx, data_rx) = channel::<OscFloatData>();
-        let (pattern_tx, pattern_rx) = tokio_channel::<WildMatch>(1);
+        let (pattern_tx, pattern_rx) = tokio_channel::<Vec<WildMatch>>(1);
 
         let found_addresses = Arc::new(Mutex::new(HashSet::new()));
         let port_changed = Arc::new(Notify::new());
@@ -69,11 +69,11 @@ impl OscServer {
         });
     }
 
-    async fn osc_thread(tx: Sender<OscFloatData>, mut pattern_rx: TokioReceiver<WildMatch>, found_addresses: Arc<Mutex<HashSet<String>>>, port_changed: Arc<Notify>, port: Arc<AtomicU16>) -> anyhow::Result<()> {
+    async fn osc_thread(tx: Sender<OscFloatData>, mut pattern_rx: TokioReceiver<Vec<WildMatch>>, found_addresses: Arc<Mutex<HashSet<String>>>, port_changed: Arc<Notify>, port: Arc<AtomicU16>) -> anyhow::Result<()> {
         loop {
             let port = port.load(Ordering::SeqCst);
             let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
-            let mut pattern = WildMatch::new("");
+            let mut patterns: Vec<WildMatch> = Vec::new();
             let mut buffer = [0; rosc::decoder::MTU];
 
             loop {
@@ -85,11 +85,17 @@ impl OscServer {
                                 continue;
                             }
 
-                            if let OscType::Float(val) = args[0] {
+                            // Integer parameters are used to select patterns
+                            let value = match args[0] {
+                                OscType::Float(val) => Some(val),
+                                OscType::Int(val) => Some(val as f32),
+                                _ => None,
+                            };
+                            if let Some(val) = value {
                                 let mut found_addresses = found_addresses.lock().expect("Could not lock");
                                 found_addresses.insert(addr.to_string());
 
-                                if !pattern.matches(&addr) {
+                                if !patterns.iter().any(|pattern| pattern.matches(&addr)) {
                                     continue;
                                 }
 
@@ -101,8 +107,8 @@ impl OscServer {
                         }
                     }
 
-                    Some(rx_pattern) = pattern_rx.recv() => {
-                        pattern = rx_pattern;
+                    Some(rx_patterns) = pattern_rx.recv() => {
+                        patterns = rx_patterns;
                     }
 
                     _ = port_changed.notified() => {
@@ -117,10 +123,10 @@ impl OscServer {
         self.data_rx.try_recv().ok()
     }
 
-    pub fn set_pattern(&mut self, pattern: WildMatch) {
+    pub fn set_patterns(&mut self, patterns: Vec<WildMatch>) {
         let pattern_tx = self.pattern_tx.clone();
         tokio::spawn(async move {
-            pattern_tx.send(pattern).await.unwrap();
+            pattern_tx.send(patterns).await.unwrap();
         });
     }
 
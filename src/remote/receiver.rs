@@ -2,7 +2,7 @@ use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 use tokio::sync::mpsc::{channel as tokio_channel, Receiver as TokioReceiver, Sender as TokioSender};
 use tokio::net::TcpListener;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::select;
 use ngrok::Session;
 use ngrok::config::ForwarderBuilder;
@@ -13,6 +13,15 @@ use ngrok::tunnel::TunnelInfo;
 use url::Url;
 use uuid::Uuid;
 
+/// Sent back after authentication, senders of older releases never read it
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Sent by senders that got the version as their first frame, a NaN that no older sender produces
+pub const CHANNEL_FRAMES_HELLO: [u8; 4] = 0x7FC0_5632u32.to_le_bytes();
+/// Channel byte followed by a little-endian f32
+pub const SPEED_FRAME_LENGTH: usize = 5;
+/// Little-endian f32 of channel 0, used by older senders and for older receivers
+pub const LEGACY_SPEED_FRAME_LENGTH: usize = 4;
+
 pub struct RemoteControlServer {
     server_rx: Receiver<ServerMessage>,
     server_tx: TokioSender<ServerCommand>,
@@ -151,18 +160,18 @@ impl RemoteControlServer {
                         let tunnel_url = tunnel_url.to_string();
                         tokio::spawn(async move {
                             let mut buffer = [0u8; 1024];
+                            let mut frames = SpeedFrameReader::default();
                             let mut is_authenticated = false;
                             loop {
                                 match stream.read(&mut buffer).await {
                                     Ok(0) => break, // Connection closed
                                     Ok(length) => {
-                                        if !is_authenticated && length == 36 {
-                                            if let Ok(token) = String::from_utf8(buffer[..length].to_vec()) {
-                                                if token == auth_token {
-                                                    is_authenticated = true;
-                                                    continue;
-                                                }
-                                            }
+                                        if !is_authenticated && length == 36
+                                            && let Ok(token) = String::from_utf8(buffer[..length].to_vec())
+                                            && token == auth_token {
+                                            is_authenticated = true;
+                                            _ = stream.write_all(&[PROTOCOL_VERSION]).await;
+                                            continue;
                                         }
 
                                         if !is_authenticated {
@@ -171,11 +180,9 @@ impl RemoteControlServer {
                                             break;
                                         }
 
-                                        buffer[..length].windows(4).for_each(|chunk| {
-                                            let _ = gui_tx.send(ServerMessage::SpeedReceived {
-                                                speed: f32::from_le_bytes(chunk.try_into().unwrap()),
-                                            });
-                                        });
+                                        for (channel, speed) in frames.push(&buffer[..length]) {
+                                            let _ = gui_tx.send(ServerMessage::SpeedReceived { channel, speed });
+                                        }
                                     }
                                     Err(e) => {
                                         eprintln!("Read error: {}", e);
@@ -198,6 +205,43 @@ impl RemoteControlServer {
     }
 }
 
+/// Splits the stream into speed frames, legacy f32 frames unless the sender opened with the hello
+#[derive(Debug, Default)]
+struct SpeedFrameReader {
+    pending: Vec<u8>,
+    channel_frames: Option<bool>, // decided by the first four bytes after authentication
+}
+
+impl SpeedFrameReader {
+    fn push(&mut self, data: &[u8]) -> Vec<(u8, f32)> {
+        self.pending.extend_from_slice(data);
+
+        let channel_frames = match self.channel_frames {
+            Some(channel_frames) => channel_frames,
+            None if self.pending.len() < CHANNEL_FRAMES_HELLO.len() => return Vec::new(),
+            None => {
+                let channel_frames = self.pending.starts_with(&CHANNEL_FRAMES_HELLO);
+                if channel_frames {
+                    self.pending.drain(..CHANNEL_FRAMES_HELLO.len());
+                }
+                *self.channel_frames.insert(channel_frames)
+            }
+        };
+
+        let frame_length = if channel_frames { SPEED_FRAME_LENGTH } else { LEGACY_SPEED_FRAME_LENGTH };
+        let frames_length = self.pending.len() - self.pending.len() % frame_length;
+        self.pending
+            .drain(..frames_length)
+            .collect::<Vec<u8>>()
+            .chunks_exact(frame_length)
+            .map(|frame| match channel_frames {
+                true => (frame[0], f32::from_le_bytes(frame[1..].try_into().unwrap())),
+                false => (0, f32::from_le_bytes(frame.try_into().unwrap())),
+            })
+            .collect()
+    }
+}
+
 struct ServerLoopState {
     active_tunnel: Option<Forwarder<TcpTunnel>>,
     active_session: Option<Session>,
@@ -220,6 +264,25 @@ pub enum ServerMessage {
     Started { url: String, token: String },
     Stopped,
     NewConnection,
-    SpeedReceived { speed: f32 },
+    SpeedReceived { channel: u8, speed: f32 },
     Error { message: String },
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_legacy_and_channel_frames() {
+        // Older senders only send channel 0 as plain f32 frames
+        let mut legacy = SpeedFrameReader::default();
+        assert_eq!(legacy.push(&0.5f32.to_le_bytes()[..2]), vec![]);
+        assert_eq!(legacy.push(&[&0.5f32.to_le_bytes()[2..], &0.25f32.to_le_bytes()[..]].concat()), vec![(0, 0.5), (0, 0.25)]);
+
+        let mut frames = SpeedFrameReader::default();
+        let mut data = CHANNEL_FRAMES_HELLO.to_vec();
+        data.push(1);
+        data.extend(0.75f32.to_le_bytes());
+        assert_eq!(frames.push(&data[..6]), vec![]);
+        assert_eq!(frames.push(&data[6..]), vec![(1, 0.75)]);
+    }
+}
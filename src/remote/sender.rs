@@ -1,10 +1,19 @@
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
 use std::net::{TcpStream};
+use std::time::{Duration, Instant};
 use url::Url;
+use crate::remote::receiver::{CHANNEL_FRAMES_HELLO, PROTOCOL_VERSION, SPEED_FRAME_LENGTH};
+
+/// How long to wait for the receiver's version, receivers of older releases never send it
+const VERSION_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub struct RemoteControlSender {
     pub code: String,
     stream: Option<TcpStream>,
+    channel_frames: Option<bool>, // the receiver understands channel frames, `None` until its version arrived or timed out
+    version_deadline: Instant,
+    pending_speeds: BTreeMap<u8, f32>, // latest speed by channel, held back until the framing is known
 }
 
 impl RemoteControlSender {
@@ -12,9 +21,13 @@ impl RemoteControlSender {
         Self {
             code: String::new(),
             stream: None,
+            channel_frames: None,
+            version_deadline: Instant::now(),
+            pending_speeds: BTreeMap::new(),
         }
     }
 
+    /// Connects and authenticates, the version handshake is finished by `poll` without blocking the caller
     pub fn connect_to(&mut self, url: Url, pairing_code: &str) -> anyhow::Result<()> {
         if let Some(stream) = self.stream.take() {
             drop(stream);
@@ -24,6 +37,9 @@ impl RemoteControlSender {
         let mut stream = TcpStream::connect(address)?;
         stream.write_all(pairing_code.as_bytes())?;
 
+        self.channel_frames = None;
+        self.version_deadline = Instant::now() + VERSION_TIMEOUT;
+        self.pending_speeds.clear();
         self.stream.replace(stream);
 
         Ok(())
@@ -33,12 +49,132 @@ impl RemoteControlSender {
         _ = self.stream.take();
         self.code = String::new();
     }
-    
-    pub fn send_speed(&mut self, speed: f32) -> anyhow::Result<()> {
+
+    /// Picks the framing once the receiver sent its version or the timeout passed, and sends the held back speeds
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(());
+        };
+        if self.channel_frames.is_some() {
+            return Ok(());
+        }
+
+        let mut version = [0u8];
+        stream.set_nonblocking(true)?;
+        let read = stream.read(&mut version);
+        stream.set_nonblocking(false)?;
+        let channel_frames = match read {
+            Ok(1) => version[0] >= PROTOCOL_VERSION,
+            Ok(_) => {
+                self.stream.take();
+                anyhow::bail!("Receiver closed the connection");
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => match Instant::now() >= self.version_deadline {
+                true => false,
+                false => return Ok(()),
+            },
+            Err(error) => {
+                self.stream.take();
+                return Err(error.into());
+            }
+        };
+
+        if channel_frames {
+            stream.write_all(&CHANNEL_FRAMES_HELLO)?;
+        }
+        self.channel_frames.replace(channel_frames);
+        for (channel, speed) in std::mem::take(&mut self.pending_speeds) {
+            self.send_speed(channel, speed)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn send_speed(&mut self, channel: u8, speed: f32) -> anyhow::Result<()> {
         if let Some(stream) = self.stream.as_mut() {
-            stream.write_all(&speed.to_le_bytes())?;
+            match self.channel_frames {
+                None => {
+                    self.pending_speeds.insert(channel, speed);
+                    return self.poll();
+                }
+                Some(true) => {
+                    let mut frame = [0u8; SPEED_FRAME_LENGTH];
+                    frame[0] = channel;
+                    frame[1..].copy_from_slice(&speed.to_le_bytes());
+                    stream.write_all(&frame)?;
+                }
+                Some(false) if channel == 0 => stream.write_all(&speed.to_le_bytes())?,
+                Some(false) => {}
+            }
         }
-        
+
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    /// Receiver that reads the pairing code, optionally answers with a version and returns everything sent after the code
+    fn receive(version: Option<u8>, send: impl FnOnce(&mut RemoteControlSender)) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut code = [0u8; 4];
+            stream.read_exact(&mut code).unwrap();
+            if let Some(version) = version {
+                stream.write_all(&[version]).unwrap();
+            }
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        let mut sender = RemoteControlSender::new();
+        sender.connect_to(url, "code").unwrap();
+        send(&mut sender);
+        sender.disconnect();
+        receiver.join().unwrap()
+    }
+
+    #[test]
+    fn falls_back_to_f32_frames_for_old_receivers() {
+        let data = receive(None, |sender| {
+            sender.send_speed(0, 0.5).unwrap();
+            sender.send_speed(1, 0.25).unwrap();
+            assert_eq!(sender.channel_frames, None);
+
+            // Nothing is sent until the version timed out
+            sender.version_deadline = Instant::now();
+            sender.poll().unwrap();
+            assert_eq!(sender.channel_frames, Some(false));
+            sender.send_speed(1, 0.75).unwrap();
+            sender.send_speed(0, 1.0).unwrap();
+        });
+
+        assert_eq!(data, [0.5f32.to_le_bytes(), 1.0f32.to_le_bytes()].concat());
+    }
+
+    #[test]
+    fn sends_channel_frames_to_new_receivers() {
+        let data = receive(Some(PROTOCOL_VERSION), |sender| {
+            sender.send_speed(1, 0.25).unwrap();
+            while sender.channel_frames.is_none() {
+                sender.poll().unwrap();
+                thread::sleep(Duration::from_millis(5));
+            }
+            sender.send_speed(0, 0.5).unwrap();
+        });
+
+        let mut expected = CHANNEL_FRAMES_HELLO.to_vec();
+        expected.push(1);
+        expected.extend(0.25f32.to_le_bytes());
+        expected.push(0);
+        expected.extend(0.5f32.to_le_bytes());
+        assert_eq!(data, expected);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use wildmatch::WildMatch;
+use crate::settings::{InputRoute, InputSource};
+use crate::speed_filter::SpeedFilter;
+
+/// Filtered intensity derived from all OSC messages matching a single address pattern
+pub struct OscChannel {
+    pattern: WildMatch,
+    filter: SpeedFilter,
+    value: f32,
+    speed: f32,
+}
+
+impl OscChannel {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: WildMatch::new(pattern),
+            filter: SpeedFilter::new(0.05),
+            value: 0.0,
+            speed: 0.0,
+        }
+    }
+
+    pub fn matches(&self, address: &str) -> bool {
+        self.pattern.matches(address)
+    }
+
+    pub fn push_value(&mut self, value: f32) {
+        self.value = value;
+    }
+
+    /// Updates the filtered speed, returns the new normalized intensity
+    pub fn update(&mut self, delta_time: f32, range_start: f32, range_end: f32) -> f32 {
+        let scaled_value = ((self.value - range_start) / (range_end - range_start)).clamp(0.0, 1.0);
+        let speed_value = self.filter.update(scaled_value, delta_time).clamp(0.0, 5.0);
+
+        self.speed = speed_value / 5.0;
+        self.speed
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+}
+
+/// Current normalized value of every input source
+#[derive(Default)]
+pub struct InputValues {
+    pub manual: Option<f32>,
+    pub manual_secondary: Option<f32>,
+    pub remote: Option<f32>,
+    pub remote_secondary: Option<f32>,
+    pub osc: HashMap<String, f32>,
+}
+
+impl InputValues {
+    pub fn get(&self, source: &InputSource) -> Option<f32> {
+        match source {
+            InputSource::Manual => self.manual,
+            InputSource::ManualSecondary => self.manual_secondary,
+            InputSource::Remote => self.remote,
+            InputSource::RemoteSecondary => self.remote_secondary,
+            InputSource::Osc(pattern) => self.osc.get(pattern).copied(),
+        }
+    }
+
+    /// Resolves the intensity for a motor (1-based) of a device. Devices without any route follow the default source,
+    /// motors with several routes use the strongest of their inputs.
+    pub fn resolve(&self, routes: &[InputRoute], device: &str, motor: u8, default_source: Option<&InputSource>) -> Option<f32> {
+        let mut device_routes = routes.iter().filter(|route| route.device == device).peekable();
+        if device_routes.peek().is_none() {
+            return default_source.and_then(|source| self.get(source));
+        }
+
+        device_routes
+            .filter(|route| route.motor.is_none_or(|route_motor| route_motor == motor))
+            .filter_map(|route| self.get(&route.source))
+            .reduce(f32::max)
+    }
+}
@@ -1,6 +1,11 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use crate::bluetooth::adv_protocol::protocols;
+use crate::bluetooth::generic::{AdvOptions, GenericToy, DEFAULT_ADV_INTERVAL_MS, MAX_ADV_INTERVAL_MS, MIN_ADV_INTERVAL_MS};
+use crate::calibration::Calibration;
 
 lazy_static! {
     static ref SETTINGS_PATH: PathBuf = {
@@ -15,10 +20,37 @@ pub struct Settings {
     pub osc_path: String,
     pub osc_range_start: f32,
     pub osc_range_end: f32,
-    pub last_ble_mac: Option<String>,
+    /// OSC address whose integer value selects the built-in pattern, 0 plays plain levels
+    #[serde(default)]
+    pub osc_pattern_path: String,
+    /// Replaced by `DeviceSettings::enabled`, only read to migrate older settings files
+    #[serde(default, skip_serializing)]
+    last_ble_mac: Option<String>,
     pub max_intensity_percent: u8,
     pub ngrok_token: Option<String>,
     pub remote_sync_local: bool,
+    #[serde(default = "Settings::default_device_settings")]
+    pub device_settings: HashMap<String, DeviceSettings>,
+    #[serde(default)]
+    pub routes: Vec<InputRoute>,
+    #[serde(default)]
+    pub gatt_adapter: Option<String>,
+    #[serde(default)]
+    pub adv_adapter: Option<String>,
+    /// Keepalive interval per protocol in milliseconds, absent or 0 disables it
+    #[serde(default)]
+    pub keepalive_ms: HashMap<String, u32>,
+    /// Seconds at level 0 after which the ADV backend stops advertising, 0 advertises forever
+    #[serde(default)]
+    pub adv_idle_stop_secs: u16,
+    #[serde(default = "Settings::default_generic_toys")]
+    pub generic_toys: Vec<GenericToy>,
+    /// Advertising interval of the generic toys in milliseconds
+    #[serde(default = "Settings::default_adv_interval_ms")]
+    pub adv_interval_ms: u16,
+    /// TX power of the generic toys in dBm, `None` uses the adapter maximum
+    #[serde(default)]
+    pub adv_tx_power: Option<i16>,
 }
 
 impl Settings {
@@ -35,18 +67,155 @@ impl Settings {
                 osc_range_start: 0.0f32,
                 osc_range_end: 1.0f32,
                 max_intensity_percent: 100,
+                device_settings: Self::default_device_settings(),
+                generic_toys: Self::default_generic_toys(),
+                adv_interval_ms: DEFAULT_ADV_INTERVAL_MS,
                 ..Default::default()
             });
         }
 
         let settings = std::fs::read_to_string((*SETTINGS_PATH).clone())?;
-        let settings: Settings = serde_json::from_str(&settings)?;
+        Self::parse(&settings)
+    }
+
+    /// Parses a settings file, older files selected a single device through `last_ble_mac`
+    /// and stored one keepalive for every ADV protocol under `generic`
+    fn parse(json: &str) -> anyhow::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let has_device_settings = value.get("device_settings").is_some();
+        let mut settings: Settings = serde_json::from_value(value)?;
+
+        // The generic toy was only selected while no address was, so it is not enabled alongside it
+        if let Some(address) = settings.last_ble_mac.take() && !has_device_settings {
+            settings.device_settings = HashMap::from([(address, DeviceSettings {
+                enabled: true,
+                ..Default::default()
+            })]);
+        }
+
+        if let Some(keepalive_ms) = settings.keepalive_ms.remove("generic") {
+            for protocol in protocols() {
+                settings.keepalive_ms.entry(protocol.id.clone()).or_insert(keepalive_ms);
+            }
+        }
         Ok(settings)
     }
+
+    pub fn device_settings(&self, identifier: &str) -> DeviceSettings {
+        self.device_settings.get(identifier).cloned().unwrap_or_default()
+    }
+
+    /// GATT adapter a device connects through, its own assignment takes precedence over the backend default
+    pub fn gatt_adapter_for(&self, identifier: &str) -> Option<String> {
+        self.device_settings(identifier).adapter.or(self.gatt_adapter.clone())
+    }
+
+    /// Keepalive interval of a protocol, `None` if it is disabled
+    pub fn keepalive_for(&self, protocol: &str) -> Option<Duration> {
+        self.keepalive_ms.get(protocol).filter(|ms| **ms > 0).map(|ms| Duration::from_millis(*ms as u64))
+    }
+
+    pub fn adv_idle_stop(&self) -> Option<Duration> {
+        (self.adv_idle_stop_secs > 0).then(|| Duration::from_secs(self.adv_idle_stop_secs as u64))
+    }
+
+    pub fn adv_options(&self) -> AdvOptions {
+        AdvOptions {
+            interval: Duration::from_millis(self.adv_interval_ms.clamp(MIN_ADV_INTERVAL_MS, MAX_ADV_INTERVAL_MS) as u64),
+            tx_power: self.adv_tx_power,
+        }
+    }
+
+    fn default_device_settings() -> HashMap<String, DeviceSettings> {
+        HashMap::from([("generic".into(), DeviceSettings {
+            enabled: true,
+            ..Default::default()
+        })])
+    }
+
+    fn default_generic_toys() -> Vec<GenericToy> {
+        vec![GenericToy::default()]
+    }
+
+    fn default_adv_interval_ms() -> u16 {
+        DEFAULT_ADV_INTERVAL_MS
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceSettings {
+    pub enabled: bool,
+    pub max_intensity_percent: u8,
+    #[serde(default)]
+    pub adapter: Option<String>,
+    #[serde(default)]
+    pub min_write_interval_ms: u16,
+    /// Name shown instead of the advertised one
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+    /// Time between two steps of the temporal dithering, `None` outputs the nearest level
+    #[serde(default)]
+    pub dither_interval_ms: Option<u16>,
+}
+
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_intensity_percent: 100,
+            adapter: None,
+            min_write_interval_ms: 0,
+            alias: None,
+            calibration: None,
+            dither_interval_ms: None,
+        }
+    }
+}
+
+/// Drives the device with the given identifier ("generic", "generic-N", or a BLE MAC) from a single input source
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct InputRoute {
+    pub source: InputSource,
+    pub device: String,
+    #[serde(default)]
+    pub motor: Option<u8>, // 1-based, None drives every motor
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum InputSource {
+    Manual,
+    ManualSecondary,
+    Remote,
+    RemoteSecondary,
+    Osc(String), // address pattern
+}
+
+impl InputSource {
+    pub fn label(&self) -> &'static str {
+        match self {
+            InputSource::Manual => "Manual",
+            InputSource::ManualSecondary => "Manual 2",
+            InputSource::Remote => "Remote",
+            InputSource::RemoteSecondary => "Remote 2",
+            InputSource::Osc(_) => "OSC",
+        }
+    }
+
+    /// Source driving the second motor of dual-motor devices when no route is configured
+    pub fn secondary(&self) -> InputSource {
+        match self {
+            InputSource::Manual | InputSource::ManualSecondary => InputSource::ManualSecondary,
+            InputSource::Remote | InputSource::RemoteSecondary => InputSource::RemoteSecondary,
+            InputSource::Osc(pattern) => InputSource::Osc(pattern.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
 pub enum ControlMode {
+    #[default]
     Manual,
     Osc,
     Remote(RemoteMode),
@@ -57,9 +226,41 @@ pub enum RemoteMode {
     Sender,
     Receiver,
 }
+#[cfg(test)]
+mod tests {
+    use crate::bluetooth::adv_protocol::CLASSIC_PROTOCOL;
+    use super::*;
 
-impl Default for ControlMode {
-    fn default() -> Self {
-        Self::Manual
+    const OLD_SETTINGS: &str = r#"{
+        "mode": "Manual",
+        "osc_port": 9001,
+        "osc_path": "/avatar/parameters/Vibe",
+        "osc_range_start": 0.0,
+        "osc_range_end": 1.0,
+        "last_ble_mac": "AA:BB:CC:DD:EE:FF",
+        "max_intensity_percent": 80,
+        "ngrok_token": null,
+        "remote_sync_local": false
+    }"#;
+
+    #[test]
+    fn migrates_old_settings_files() {
+        let settings = Settings::parse(OLD_SETTINGS).unwrap();
+        assert!(settings.device_settings("AA:BB:CC:DD:EE:FF").enabled);
+        assert!(!settings.device_settings("generic").enabled);
+        assert_eq!(settings.max_intensity_percent, 80);
+
+        // Without an address the generic toy was the selected device
+        let settings = Settings::parse(&OLD_SETTINGS.replace(r#""AA:BB:CC:DD:EE:FF""#, "null")).unwrap();
+        assert!(settings.device_settings("generic").enabled);
+        assert_eq!(settings.device_settings.len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn migrates_generic_keepalive_to_adv_protocols() {
+        let settings = Settings::parse(&OLD_SETTINGS.replace(r#""mode""#, r#""keepalive_ms": { "generic": 500, "lovense": 1000 }, "mode""#)).unwrap();
+        assert_eq!(settings.keepalive_for(CLASSIC_PROTOCOL), Some(Duration::from_millis(500)));
+        assert_eq!(settings.keepalive_for("lovense"), Some(Duration::from_secs(1)));
+        assert!(!settings.keepalive_ms.contains_key("generic"));
+    }
+}